@@ -0,0 +1,428 @@
+use crate::{
+    devices::{DeviceProfile, BLOCK_SIZE},
+    error::KrakenError,
+};
+use std::path::PathBuf;
+
+/// Where the content of one bucket lives in device memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Allocation {
+    pub start: u16,
+    pub blocks: u16,
+    /// payload size in bytes
+    pub size: usize,
+    pub is_gif: bool,
+}
+
+impl Allocation {
+    fn end(&self) -> u16 {
+        self.start + self.blocks
+    }
+}
+
+/// Buckets packed into device memory, each sized to its payload.
+/// With a manifest, the layout is kept in a file so later runs know what each bucket holds
+pub struct BucketMap {
+    memory_blocks: u16,
+    buckets: Vec<Option<Allocation>>,
+    /// whether the map matches the device, only once buckets are cleared or a manifest is loaded
+    known: bool,
+    manifest: Option<PathBuf>,
+    /// bucket on screen, so the next frame can go into another one
+    shown: Option<u8>,
+    /// layout last written to or read from the manifest, None if there is none
+    saved: Option<String>,
+    saved_shown: Option<u8>,
+}
+
+impl BucketMap {
+    /// Map of a device's buckets, kept in the manifest file if given
+    pub fn new(profile: &DeviceProfile, manifest: Option<PathBuf>) -> Self {
+        BucketMap {
+            memory_blocks: profile.memory_blocks,
+            buckets: vec![None; profile.bucket_count as usize],
+            known: false,
+            manifest,
+            shown: None,
+            saved: None,
+            saved_shown: None,
+        }
+    }
+
+    /// Read the manifest if one was written before
+    pub fn load(&mut self) -> Result<(), KrakenError> {
+        let Some(path) = self.manifest.clone().filter(|p| p.exists()) else {
+            return Ok(());
+        };
+        let text = std::fs::read_to_string(&path).map_err(|e| KrakenError::file(&path, e))?;
+        if let Err(e) = self.parse(&text) {
+            self.forget();
+            return Err(KrakenError::Invalid(format!("{}: {e}", path.display())));
+        }
+        self.known = true;
+        self.saved = Some(self.to_text());
+        self.saved_shown = self.shown;
+        Ok(())
+    }
+
+    /// Manifest file for a device, by serial if it has one
+    pub fn manifest_path(profile: &DeviceProfile, serial: Option<&str>) -> Option<PathBuf> {
+        let mut path = dirs::config_dir()?;
+        path.push("krakenctl");
+        path.push(match serial {
+            Some(serial) => format!("buckets-{serial}.txt"),
+            None => format!("buckets-{:04x}.txt", profile.pid),
+        });
+        Some(path)
+    }
+
+    pub fn known(&self) -> bool {
+        self.known
+    }
+
+    pub fn shown(&self) -> Option<u8> {
+        self.shown
+    }
+
+    /// The bucket now on screen, or None when the screen shows something else
+    pub fn set_shown(&mut self, index: Option<u8>) {
+        self.shown = index;
+    }
+
+    pub fn get(&self, index: u8) -> Option<Allocation> {
+        self.buckets.get(index as usize).copied().flatten()
+    }
+
+    /// blocks in use by all buckets
+    pub fn used_blocks(&self) -> u16 {
+        self.buckets.iter().flatten().map(|a| a.blocks).sum()
+    }
+
+    /// Find room for a payload in a bucket, in the first free space large enough.
+    /// Whatever the bucket held before is freed. The allocation is kept once inserted
+    pub fn allocate(
+        &mut self,
+        index: u8,
+        size: usize,
+        is_gif: bool,
+    ) -> Result<Allocation, KrakenError> {
+        self.free(index);
+        let blocks = size.div_ceil(BLOCK_SIZE).max(1);
+        let mut used = self.buckets.iter().flatten().copied().collect::<Vec<_>>();
+        used.sort_by_key(|a| a.start);
+
+        //gaps between buckets, and after the last one
+        let mut largest = 0;
+        let mut start = 0;
+        for next in used.iter().map(Some).chain([None]) {
+            let end = next.map(|a| a.start).unwrap_or(self.memory_blocks);
+            let gap = end.saturating_sub(start) as usize;
+            if gap >= blocks {
+                return Ok(Allocation {
+                    start,
+                    blocks: blocks as u16,
+                    size,
+                    is_gif,
+                });
+            }
+            largest = largest.max(gap);
+            if let Some(next) = next {
+                start = start.max(next.end());
+            }
+        }
+        Err(KrakenError::Invalid(format!(
+            "{} KiB does not fit in device memory, the largest free space is {largest} KiB of {} KiB. Delete or clear buckets to make room",
+            blocks,
+            self.memory_blocks
+        )))
+    }
+
+    pub fn insert(&mut self, index: u8, allocation: Allocation) {
+        self.buckets[index as usize] = Some(allocation);
+    }
+
+    pub fn free(&mut self, index: u8) {
+        if let Some(bucket) = self.buckets.get_mut(index as usize) {
+            *bucket = None;
+        }
+        if self.shown == Some(index) {
+            self.shown = None;
+        }
+    }
+
+    /// All buckets were cleared on the device, so the map matches it from now on
+    pub fn clear(&mut self) {
+        self.buckets.fill(None);
+        self.shown = None;
+        self.known = true;
+    }
+
+    /// The device lost or changed its buckets without us, e.g. after a reconnect
+    pub fn forget(&mut self) {
+        self.buckets.fill(None);
+        self.shown = None;
+        self.known = false;
+    }
+
+    /// Write the manifest if the layout changed since it was last written,
+    /// or remove it when the layout is no longer known
+    pub fn save(&mut self) -> Result<(), KrakenError> {
+        self.write(false)
+    }
+
+    /// Like save, but also write if only the shown bucket changed.
+    /// That changes with every frame, so is only worth writing at the end of a run
+    pub fn save_shown(&mut self) -> Result<(), KrakenError> {
+        self.write(true)
+    }
+
+    fn write(&mut self, with_shown: bool) -> Result<(), KrakenError> {
+        let Some(path) = &self.manifest else {
+            return Ok(());
+        };
+        let layout = self.known.then(|| self.to_text());
+        if layout == self.saved && (!with_shown || self.shown == self.saved_shown) {
+            return Ok(());
+        }
+        match &layout {
+            None => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(KrakenError::file(path, e))
+                }
+                _ => (),
+            },
+            Some(text) => {
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir).map_err(|e| KrakenError::file(path, e))?;
+                }
+                let shown = match self.shown {
+                    Some(index) => format!("shown {index}\n"),
+                    None => String::new(),
+                };
+                std::fs::write(path, format!("{text}{shown}"))
+                    .map_err(|e| KrakenError::file(path, e))?;
+            }
+        }
+        self.saved = layout;
+        self.saved_shown = self.shown;
+        Ok(())
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = "#bucket start_block blocks bytes type\n".to_string();
+        for (i, a) in self.buckets.iter().enumerate() {
+            if let Some(a) = a {
+                text.push_str(&format!(
+                    "{i} {} {} {} {}\n",
+                    a.start,
+                    a.blocks,
+                    a.size,
+                    if a.is_gif { "gif" } else { "rgba" }
+                ));
+            }
+        }
+        text
+    }
+
+    fn parse(&mut self, text: &str) -> Result<(), String> {
+        for line in text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        {
+            let parts = line.split_whitespace().collect::<Vec<&str>>();
+            if let ["shown", index] = parts[..] {
+                self.shown = index
+                    .parse::<u8>()
+                    .ok()
+                    .filter(|i| (*i as usize) < self.buckets.len());
+                continue;
+            }
+            let [index, start, blocks, size, kind] = parts[..] else {
+                return Err(format!("bad line '{line}'"));
+            };
+            let number = |s: &str| s.parse::<usize>().map_err(|_| format!("bad line '{line}'"));
+            let (index, start, blocks, size) = (
+                number(index)?,
+                number(start)?,
+                number(blocks)?,
+                number(size)?,
+            );
+            if index >= self.buckets.len() || start + blocks > self.memory_blocks as usize {
+                return Err(format!("bucket '{line}' is outside device memory"));
+            }
+            if blocks == 0 {
+                return Err(format!("bucket '{line}' has no blocks"));
+            }
+            let allocation = Allocation {
+                start: start as u16,
+                blocks: blocks as u16,
+                size,
+                is_gif: kind == "gif",
+            };
+            if self
+                .buckets
+                .iter()
+                .flatten()
+                .any(|a| a.start < allocation.end() && allocation.start < a.end())
+            {
+                return Err(format!("bucket '{line}' overlaps another bucket"));
+            }
+            self.buckets[index] = Some(allocation);
+        }
+        Ok(())
+    }
+
+    /// Buckets as text, one per line, with memory use at the end
+    pub fn describe(&self) -> String {
+        let mut lines = (0..self.buckets.len() as u8)
+            .map(|i| match self.get(i) {
+                Some(a) => format!(
+                    "{i:>2} blocks {}-{} {} bytes {}",
+                    a.start,
+                    a.end() - 1,
+                    a.size,
+                    if a.is_gif { "gif" } else { "rgba" }
+                ),
+                None if self.known => format!("{i:>2} empty"),
+                None => format!("{i:>2} unknown"),
+            })
+            .collect::<Vec<String>>();
+        lines.push(format!(
+            "{} of {} KiB used",
+            self.used_blocks(),
+            self.memory_blocks
+        ));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices;
+
+    fn map(manifest: Option<PathBuf>) -> BucketMap {
+        let mut map = BucketMap::new(devices::default_profile(), manifest);
+        map.clear();
+        map
+    }
+
+    fn insert(map: &mut BucketMap, index: u8, size: usize) -> Allocation {
+        let allocation = map.allocate(index, size, false).unwrap();
+        map.insert(index, allocation);
+        allocation
+    }
+
+    #[test]
+    fn allocate_packs_buckets_by_size() {
+        let mut map = map(None);
+        assert_eq!(insert(&mut map, 0, 1).start, 0);
+        let second = insert(&mut map, 1, 3 * BLOCK_SIZE + 1);
+        assert_eq!((second.start, second.blocks), (1, 4));
+        assert_eq!(insert(&mut map, 2, BLOCK_SIZE).start, 5);
+        assert_eq!(map.used_blocks(), 6);
+    }
+
+    #[test]
+    fn allocate_reuses_freed_space() {
+        let mut map = map(None);
+        insert(&mut map, 0, 4 * BLOCK_SIZE);
+        insert(&mut map, 1, BLOCK_SIZE);
+        map.free(0);
+        //fits in the gap bucket 0 left
+        assert_eq!(insert(&mut map, 2, 2 * BLOCK_SIZE).start, 0);
+        //does not, so goes after bucket 1
+        assert_eq!(insert(&mut map, 3, 3 * BLOCK_SIZE).start, 5);
+    }
+
+    #[test]
+    fn allocate_replaces_what_the_bucket_held() {
+        let mut map = map(None);
+        insert(&mut map, 0, 2 * BLOCK_SIZE);
+        let again = insert(&mut map, 0, 3 * BLOCK_SIZE);
+        assert_eq!((again.start, again.blocks), (0, 3));
+        assert_eq!(map.used_blocks(), 3);
+    }
+
+    #[test]
+    fn allocate_reports_when_full() {
+        let mut map = map(None);
+        let memory = devices::default_profile().memory_blocks as usize;
+        insert(&mut map, 0, (memory - 10) * BLOCK_SIZE);
+        let error = map.allocate(1, 11 * BLOCK_SIZE, true).unwrap_err();
+        assert!(error.to_string().contains("largest free space is 10 KiB"));
+        assert!(map.allocate(1, 10 * BLOCK_SIZE, true).is_ok());
+    }
+
+    #[test]
+    fn manifest_round_trip_and_only_saved_on_change() {
+        let path =
+            std::env::temp_dir().join(format!("krakenctl-buckets-{}.txt", std::process::id()));
+        let mut saved = map(Some(path.clone()));
+        insert(&mut saved, 0, 2000);
+        saved.insert(
+            3,
+            Allocation {
+                start: 100,
+                blocks: 50,
+                size: 50000,
+                is_gif: true,
+            },
+        );
+        saved.set_shown(Some(3));
+        saved.save().unwrap();
+
+        //unchanged layout is not written again
+        std::fs::remove_file(&path).unwrap();
+        saved.save().unwrap();
+        assert!(!path.exists());
+        insert(&mut saved, 1, 10);
+        saved.save().unwrap();
+        //the shown bucket is only written with save_shown
+        saved.set_shown(Some(1));
+        saved.save().unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("shown 3"));
+        saved.save_shown().unwrap();
+
+        let mut loaded = BucketMap::new(devices::default_profile(), Some(path.clone()));
+        loaded.load().unwrap();
+        assert!(loaded.known());
+        for i in 0..16 {
+            assert_eq!(loaded.get(i), saved.get(i));
+        }
+        assert_eq!(loaded.shown(), Some(1));
+
+        //forgetting the layout removes the manifest
+        loaded.forget();
+        loaded.save().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn manifest_outside_memory_is_rejected() {
+        let mut map = BucketMap::new(devices::default_profile(), None);
+        assert!(map.parse("0 24300 100 1000 rgba").is_err());
+        assert!(map.parse("16 0 1 1000 rgba").is_err());
+        assert!(map.parse("0 0 1").is_err());
+    }
+
+    #[test]
+    fn manifest_with_empty_or_overlapping_buckets_is_forgotten() {
+        let mut map = BucketMap::new(devices::default_profile(), None);
+        assert!(map.parse("0 0 0 0 rgba").is_err());
+        let mut map = BucketMap::new(devices::default_profile(), None);
+        assert!(map.parse("0 0 10 10000 rgba\n1 9 5 5000 rgba").is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("krakenctl-overlapping-{}.txt", std::process::id()));
+        std::fs::write(&path, "0 0 10 10000 rgba\n1 5 10 10000 gif\n").unwrap();
+        let mut loaded = BucketMap::new(devices::default_profile(), Some(path.clone()));
+        let result = loaded.load();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(KrakenError::Invalid(_))));
+        assert!(!loaded.known());
+        assert_eq!(loaded.get(0), None);
+        assert!(loaded.describe().contains(" 0 unknown"));
+    }
+}
@@ -0,0 +1,574 @@
+use crate::{
+    devices,
+    error::KrakenError,
+    protocol::BULK_MAGIC,
+    record::{Direction, Event},
+    transport::Kind,
+};
+use std::{collections::HashMap, path::Path, time::Duration};
+
+//link types of usb captures
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+const LINKTYPE_USBPCAP: u32 = 249;
+
+//usb transfer types, the same in usbmon and USBPcap
+const TRANSFER_INTERRUPT: u8 = 1;
+const TRANSFER_CONTROL: u8 = 2;
+const TRANSFER_BULK: u8 = 3;
+
+//commands only a Kraken is sent, to find it in captures without device descriptors
+const KRAKEN_COMMANDS: [[u8; 2]; 13] = [
+    [0x10, 0x01],
+    [0x30, 0x01],
+    [0x30, 0x02],
+    [0x30, 0x04],
+    [0x32, 0x01],
+    [0x32, 0x02],
+    [0x36, 0x01],
+    [0x36, 0x02],
+    [0x38, 0x01],
+    [0x72, 0x01],
+    [0x72, 0x02],
+    [0x74, 0x01],
+    [0x2a, 0x04],
+];
+
+/// One captured frame, before it is known to be usb
+struct Frame<'a> {
+    link_type: u32,
+    /// seconds
+    time: f64,
+    data: &'a [u8],
+}
+
+/// A usb transfer that carries data, from either capture format
+struct Transfer {
+    time: f64,
+    bus: u16,
+    device: u16,
+    transfer_type: u8,
+    /// with the 0x80 bit set for IN
+    endpoint: u8,
+    data: Vec<u8>,
+}
+
+fn u16_at(data: &[u8], at: usize, le: bool) -> Option<u16> {
+    let bytes: [u8; 2] = data.get(at..at + 2)?.try_into().ok()?;
+    Some(if le {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn u32_at(data: &[u8], at: usize, le: bool) -> Option<u32> {
+    let bytes: [u8; 4] = data.get(at..at + 4)?.try_into().ok()?;
+    Some(if le {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+//classic pcap: 24 byte header, then 16 byte record headers
+fn pcap_frames(file: &[u8]) -> Result<Vec<Frame<'_>>, String> {
+    let (le, nanos) = match u32_at(file, 0, true) {
+        Some(0xa1b2c3d4) => (true, false),
+        Some(0xa1b23c4d) => (true, true),
+        Some(0xd4c3b2a1) => (false, false),
+        Some(0x4d3cb2a1) => (false, true),
+        _ => return Err("not a pcap file".to_string()),
+    };
+    let link_type = u32_at(file, 20, le).ok_or("truncated pcap header")?;
+    let mut frames = vec![];
+    let mut at = 24;
+    while let (Some(sec), Some(frac), Some(len)) = (
+        u32_at(file, at, le),
+        u32_at(file, at + 4, le),
+        u32_at(file, at + 8, le),
+    ) {
+        let start = at + 16;
+        let Some(data) = file.get(start..start + len as usize) else {
+            break;
+        };
+        let frac = if nanos {
+            frac as f64 / 1e9
+        } else {
+            frac as f64 / 1e6
+        };
+        frames.push(Frame {
+            link_type,
+            time: sec as f64 + frac,
+            data,
+        });
+        at = start + len as usize;
+    }
+    Ok(frames)
+}
+
+//pcapng: blocks of type, length, body, length
+//interfaces are described before the packets that use them
+fn pcapng_frames(file: &[u8]) -> Result<Vec<Frame<'_>>, String> {
+    const SECTION_HEADER: u32 = 0x0a0d0d0a;
+    const INTERFACE: u32 = 1;
+    const SIMPLE_PACKET: u32 = 3;
+    const ENHANCED_PACKET: u32 = 6;
+
+    let mut frames = vec![];
+    //link type and seconds per tick of each interface in the section
+    let mut interfaces: Vec<(u32, f64)> = vec![];
+    let mut le = true;
+    let mut at = 0;
+    while let Some(block_type) = u32_at(file, at, le) {
+        if block_type == SECTION_HEADER {
+            le = match u32_at(file, at + 8, true) {
+                Some(0x1a2b3c4d) => true,
+                Some(0x4d3c2b1a) => false,
+                _ => return Err("bad pcapng byte order".to_string()),
+            };
+            interfaces.clear();
+        }
+        let len = u32_at(file, at + 4, le).ok_or("truncated pcapng block")? as usize;
+        let Some(body) = file.get(at + 8..(at + len).saturating_sub(4)) else {
+            break;
+        };
+        if len < 12 {
+            return Err("bad pcapng block length".to_string());
+        }
+        match block_type {
+            INTERFACE => {
+                let link_type = u16_at(body, 0, le).unwrap_or_default() as u32;
+                interfaces.push((
+                    link_type,
+                    interface_resolution(&body[8.min(body.len())..], le),
+                ));
+            }
+            ENHANCED_PACKET => {
+                let (Some(id), Some(high), Some(low), Some(caplen)) = (
+                    u32_at(body, 0, le),
+                    u32_at(body, 4, le),
+                    u32_at(body, 8, le),
+                    u32_at(body, 12, le),
+                ) else {
+                    break;
+                };
+                let (link_type, resolution) = interfaces
+                    .get(id as usize)
+                    .copied()
+                    .ok_or("packet for an unknown interface")?;
+                if let Some(data) = body.get(20..20 + caplen as usize) {
+                    let ticks = ((high as u64) << 32) | low as u64;
+                    frames.push(Frame {
+                        link_type,
+                        time: ticks as f64 * resolution,
+                        data,
+                    });
+                }
+            }
+            SIMPLE_PACKET => {
+                let (link_type, _) = interfaces.first().copied().ok_or("no interface")?;
+                if let Some(data) = body.get(4..) {
+                    frames.push(Frame {
+                        link_type,
+                        time: 0.0,
+                        data,
+                    });
+                }
+            }
+            _ => (),
+        }
+        at += len;
+    }
+    Ok(frames)
+}
+
+//if_tsresol option, microseconds if not given
+fn interface_resolution(options: &[u8], le: bool) -> f64 {
+    let mut at = 0;
+    while let (Some(code), Some(len)) = (u16_at(options, at, le), u16_at(options, at + 2, le)) {
+        if code == 0 {
+            break;
+        }
+        if code == 9 {
+            if let Some(&res) = options.get(at + 4) {
+                return if res & 0x80 == 0 {
+                    10f64.powi(-(res as i32))
+                } else {
+                    2f64.powi(-((res & 0x7f) as i32))
+                };
+            }
+        }
+        at += 4 + (len as usize).div_ceil(4) * 4;
+    }
+    1e-6
+}
+
+//linux usbmon: OUT data is in the submission, IN data in the completion
+fn usbmon_transfer(frame: &Frame) -> Option<Transfer> {
+    let data = frame.data;
+    let header = match frame.link_type {
+        LINKTYPE_USB_LINUX_MMAPPED => 64,
+        _ => 48,
+    };
+    let event = *data.get(8)?;
+    let transfer_type = *data.get(9)?;
+    let endpoint = *data.get(10)?;
+    let device = *data.get(11)? as u16;
+    let bus = u16_at(data, 12, true)?;
+    let payload = data.get(header..)?;
+    let wanted = match endpoint & 0x80 {
+        0 => b'S',
+        _ => b'C',
+    };
+    if event != wanted || payload.is_empty() {
+        return None;
+    }
+    Some(Transfer {
+        time: frame.time,
+        bus,
+        device,
+        transfer_type,
+        endpoint,
+        data: payload.to_vec(),
+    })
+}
+
+//windows USBPcap: bit 0 of info is set on completions
+fn usbpcap_transfer(frame: &Frame) -> Option<Transfer> {
+    let data = frame.data;
+    let header = u16_at(data, 0, true)? as usize;
+    let completion = data.get(16)? & 1 == 1;
+    let bus = u16_at(data, 17, true)?;
+    let device = u16_at(data, 19, true)?;
+    let endpoint = *data.get(21)?;
+    let transfer_type = *data.get(22)?;
+    let payload = data.get(header..)?;
+    if completion != (endpoint & 0x80 != 0) || payload.is_empty() {
+        return None;
+    }
+    Some(Transfer {
+        time: frame.time,
+        bus,
+        device,
+        transfer_type,
+        endpoint,
+        data: payload.to_vec(),
+    })
+}
+
+//a 64 byte Kraken command, or the header of a bulk upload
+fn is_kraken_transfer(transfer: &Transfer) -> bool {
+    if transfer.endpoint & 0x80 != 0 {
+        return false;
+    }
+    match transfer.transfer_type {
+        TRANSFER_INTERRUPT => {
+            transfer.data.len() == 64
+                && KRAKEN_COMMANDS.contains(&[transfer.data[0], transfer.data[1]])
+        }
+        TRANSFER_BULK => transfer.data.starts_with(&BULK_MAGIC),
+        _ => false,
+    }
+}
+
+/// Read usb transfers to and from a Kraken in a usbmon or USBPcap capture, in pcap or pcapng format.
+/// Devices are recognised from the device descriptors in the capture. Captures started after
+/// the cooler was plugged in have none, so then devices sent Kraken commands are kept,
+/// and if there are none of those either, every device is kept
+pub fn load(path: &Path) -> Result<Vec<Event>, KrakenError> {
+    let file = std::fs::read(path).map_err(|e| KrakenError::file(path, e))?;
+    let invalid = |e: String| KrakenError::Invalid(format!("{}: {e}", path.display()));
+    let frames = match u32_at(&file, 0, true) {
+        Some(0x0a0d0d0a) => pcapng_frames(&file),
+        _ => pcap_frames(&file),
+    }
+    .map_err(invalid)?;
+
+    let transfers = frames
+        .iter()
+        .filter_map(|frame| match frame.link_type {
+            LINKTYPE_USB_LINUX | LINKTYPE_USB_LINUX_MMAPPED => usbmon_transfer(frame),
+            LINKTYPE_USBPCAP => usbpcap_transfer(frame),
+            _ => None,
+        })
+        .collect::<Vec<Transfer>>();
+    if transfers.is_empty() && !frames.is_empty() {
+        return Err(invalid(
+            "no usb transfers, is this a usbmon or USBPcap capture?".to_string(),
+        ));
+    }
+
+    //device descriptors are 18 bytes of type 1, with the vid and pid at 8 and 10
+    let mut ids: HashMap<(u16, u16), (u16, u16)> = HashMap::new();
+    for transfer in transfers
+        .iter()
+        .filter(|t| t.transfer_type == TRANSFER_CONTROL)
+    {
+        if let [18, 1, ..] = transfer.data[..] {
+            if let (Some(vid), Some(pid)) = (
+                u16_at(&transfer.data, 8, true),
+                u16_at(&transfer.data, 10, true),
+            ) {
+                ids.insert((transfer.bus, transfer.device), (vid, pid));
+            }
+        }
+    }
+    let mut krakens = ids
+        .iter()
+        .filter(|(_, (vid, pid))| devices::find(*vid, *pid).is_some())
+        .map(|(device, _)| *device)
+        .collect::<Vec<(u16, u16)>>();
+    if krakens.is_empty() {
+        krakens = transfers
+            .iter()
+            .filter(|t| is_kraken_transfer(t))
+            .map(|t| (t.bus, t.device))
+            .collect();
+        krakens.sort();
+        krakens.dedup();
+        if krakens.is_empty() {
+            eprintln!("No Kraken device descriptor or commands in capture, showing all devices");
+        }
+    }
+
+    let start = transfers.first().map(|t| t.time).unwrap_or_default();
+    Ok(transfers
+        .into_iter()
+        .filter(|t| matches!(t.transfer_type, TRANSFER_INTERRUPT | TRANSFER_BULK))
+        .filter(|t| krakens.is_empty() || krakens.contains(&(t.bus, t.device)))
+        .map(|t| Event {
+            time: Duration::from_secs_f64((t.time - start).max(0.0)),
+            kind: match t.transfer_type {
+                TRANSFER_BULK => Kind::Bulk,
+                _ => Kind::Interrupt,
+            },
+            direction: match t.endpoint & 0x80 {
+                0 => Direction::Out,
+                _ => Direction::In,
+            },
+            endpoint: t.endpoint,
+            data: t.data,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KRAKEN_DEVICE: u8 = 5;
+    const OTHER_DEVICE: u8 = 6;
+
+    fn descriptor() -> Vec<u8> {
+        let profile = &devices::DEVICES[0];
+        let mut descriptor = vec![18, 1, 0, 2, 0, 0, 0, 64];
+        descriptor.extend(profile.vid.to_le_bytes());
+        descriptor.extend(profile.pid.to_le_bytes());
+        descriptor.extend([0; 6]);
+        descriptor
+    }
+
+    //start of a bulk upload
+    fn protocol_header() -> Vec<u8> {
+        let mut header = crate::protocol::BULK_MAGIC.to_vec();
+        header.resize(512, 0);
+        header
+    }
+
+    fn usbmon(event: u8, transfer_type: u8, endpoint: u8, device: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 48];
+        packet[8] = event;
+        packet[9] = transfer_type;
+        packet[10] = endpoint;
+        packet[11] = device;
+        packet[12..14].copy_from_slice(&1u16.to_le_bytes());
+        packet.extend(data);
+        packet
+    }
+
+    fn usbpcap(completion: bool, transfer_type: u8, endpoint: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 27];
+        packet[0..2].copy_from_slice(&27u16.to_le_bytes());
+        packet[16] = completion as u8;
+        packet[17..19].copy_from_slice(&1u16.to_le_bytes());
+        packet[19..21].copy_from_slice(&(KRAKEN_DEVICE as u16).to_le_bytes());
+        packet[21] = endpoint;
+        packet[22] = transfer_type;
+        packet.extend(data);
+        packet
+    }
+
+    //microsecond pcap with one packet per 1.5 seconds
+    fn pcap(link_type: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = vec![];
+        file.extend(0xa1b2c3d4u32.to_le_bytes());
+        file.extend(2u16.to_le_bytes());
+        file.extend(4u16.to_le_bytes());
+        file.extend([0; 8]);
+        file.extend(65535u32.to_le_bytes());
+        file.extend(link_type.to_le_bytes());
+        for (i, packet) in packets.iter().enumerate() {
+            file.extend((i as u32 * 3 / 2).to_le_bytes());
+            file.extend((i as u32 % 2 * 500_000).to_le_bytes());
+            file.extend((packet.len() as u32).to_le_bytes());
+            file.extend((packet.len() as u32).to_le_bytes());
+            file.extend(packet);
+        }
+        file
+    }
+
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let padded = body.len().div_ceil(4) * 4;
+        let len = (12 + padded) as u32;
+        let mut block = vec![];
+        block.extend(block_type.to_le_bytes());
+        block.extend(len.to_le_bytes());
+        block.extend(body);
+        block.resize(8 + padded, 0);
+        block.extend(len.to_le_bytes());
+        block
+    }
+
+    //nanosecond pcapng with one packet per 2 milliseconds
+    fn pcapng(link_type: u16, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut section = vec![];
+        section.extend(0x1a2b3c4du32.to_le_bytes());
+        section.extend(1u16.to_le_bytes());
+        section.extend(0u16.to_le_bytes());
+        section.extend((-1i64).to_le_bytes());
+        let mut file = pcapng_block(0x0a0d0d0a, &section);
+
+        let mut interface = vec![];
+        interface.extend((link_type as u32).to_le_bytes());
+        interface.extend(65535u32.to_le_bytes());
+        //if_tsresol of 10^-9, then the end of options
+        interface.extend([9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        file.extend(pcapng_block(1, &interface));
+
+        for (i, packet) in packets.iter().enumerate() {
+            let ticks = i as u64 * 2_000_000;
+            let mut body = vec![];
+            body.extend(0u32.to_le_bytes());
+            body.extend(((ticks >> 32) as u32).to_le_bytes());
+            body.extend((ticks as u32).to_le_bytes());
+            body.extend((packet.len() as u32).to_le_bytes());
+            body.extend((packet.len() as u32).to_le_bytes());
+            body.extend(packet);
+            file.extend(pcapng_block(6, &body));
+        }
+        file
+    }
+
+    fn load_bytes(name: &str, bytes: &[u8]) -> Result<Vec<Event>, KrakenError> {
+        let path = std::env::temp_dir().join(format!("krakenctl-{name}-{}", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        let events = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        events
+    }
+
+    #[test]
+    fn pcap_usbmon_keeps_kraken_transfers() {
+        let packets = [
+            usbmon(b'C', TRANSFER_CONTROL, 0x80, KRAKEN_DEVICE, &descriptor()),
+            usbmon(b'S', TRANSFER_INTERRUPT, 0x01, KRAKEN_DEVICE, &[0x74, 0x01]),
+            //an IN submission has no data yet, and an OUT completion repeats nothing
+            usbmon(b'S', TRANSFER_INTERRUPT, 0x81, KRAKEN_DEVICE, &[]),
+            usbmon(b'C', TRANSFER_INTERRUPT, 0x01, KRAKEN_DEVICE, &[]),
+            usbmon(b'C', TRANSFER_INTERRUPT, 0x81, KRAKEN_DEVICE, &[0x75, 0x01]),
+            usbmon(b'S', TRANSFER_INTERRUPT, 0x01, OTHER_DEVICE, &[0x74, 0x01]),
+        ];
+        let events = load_bytes("usbmon.pcap", &pcap(LINKTYPE_USB_LINUX, &packets)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].kind, events[0].direction, events[0].endpoint),
+            (Kind::Interrupt, Direction::Out, 0x01)
+        );
+        assert_eq!(events[0].data, vec![0x74, 0x01]);
+        assert_eq!(events[0].time, Duration::from_millis(1500));
+        assert_eq!(events[1].direction, Direction::In);
+        assert_eq!(events[1].data, vec![0x75, 0x01]);
+        assert_eq!(events[1].time, Duration::from_secs(6));
+    }
+
+    #[test]
+    fn pcapng_usbpcap_keeps_bulk_writes() {
+        let packets = [
+            usbpcap(true, TRANSFER_CONTROL, 0x80, &descriptor()),
+            usbpcap(false, TRANSFER_INTERRUPT, 0x01, &[0x36, 0x01, 0x00]),
+            usbpcap(false, TRANSFER_BULK, 0x02, &protocol_header()),
+            usbpcap(true, TRANSFER_BULK, 0x02, &[]),
+        ];
+        let events =
+            load_bytes("usbpcap.pcapng", &pcapng(LINKTYPE_USBPCAP as u16, &packets)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, Kind::Interrupt);
+        assert_eq!(events[1].kind, Kind::Bulk);
+        assert_eq!(events[1].endpoint, 0x02);
+        assert_eq!(events[1].data, protocol_header());
+        assert_eq!(events[1].time, Duration::from_millis(4));
+    }
+
+    fn command(bytes: &[u8]) -> Vec<u8> {
+        let mut command = bytes.to_vec();
+        command.resize(64, 0);
+        command
+    }
+
+    #[test]
+    fn without_descriptors_kraken_is_found_by_its_commands() {
+        let packets = [
+            //a mouse reporting movement
+            usbmon(
+                b'C',
+                TRANSFER_INTERRUPT,
+                0x81,
+                OTHER_DEVICE,
+                &[0, 1, 255, 0],
+            ),
+            usbmon(
+                b'S',
+                TRANSFER_INTERRUPT,
+                0x01,
+                KRAKEN_DEVICE,
+                &command(&[0x74, 0x01]),
+            ),
+            usbmon(
+                b'C',
+                TRANSFER_INTERRUPT,
+                0x81,
+                KRAKEN_DEVICE,
+                &command(&[0x75, 0x01]),
+            ),
+            usbmon(b'S', TRANSFER_BULK, 0x02, OTHER_DEVICE, &[0x74, 0x01]),
+        ];
+        let events = load_bytes("found.pcap", &pcap(LINKTYPE_USB_LINUX, &packets)).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].data[..2], [0x74, 0x01]);
+        assert_eq!(events[1].data[..2], [0x75, 0x01]);
+    }
+
+    #[test]
+    fn without_descriptors_or_commands_every_device_is_kept() {
+        let packets = [
+            usbmon(b'S', TRANSFER_INTERRUPT, 0x01, KRAKEN_DEVICE, &[0x74, 0x01]),
+            usbmon(b'S', TRANSFER_INTERRUPT, 0x01, OTHER_DEVICE, &[0x74, 0x01]),
+        ];
+        let events = load_bytes("any.pcap", &pcap(LINKTYPE_USB_LINUX, &packets)).unwrap();
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(matches!(
+            load_bytes("text.pcap", b"not a capture at all"),
+            Err(KrakenError::Invalid(_))
+        ));
+        //a network capture has frames but no usb transfers
+        assert!(matches!(
+            load_bytes("ethernet.pcap", &pcap(1, &[vec![0; 60]])),
+            Err(KrakenError::Invalid(_))
+        ));
+    }
+}
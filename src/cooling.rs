@@ -0,0 +1,179 @@
+use crate::error::KrakenError;
+use std::fmt::Display;
+
+//the device takes a duty for each liquid temperature from 20°C to 59°C
+pub const PROFILE_START_TEMP: u8 = 20;
+pub const PROFILE_POINTS: usize = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    Pump,
+    Fan,
+}
+
+impl Channel {
+    pub fn id(&self) -> u8 {
+        match self {
+            Channel::Pump => 0x01,
+            Channel::Fan => 0x02,
+        }
+    }
+
+    /// lowest duty allowed, the pump must keep liquid moving
+    pub fn min_duty(&self) -> u8 {
+        match self {
+            Channel::Pump => 20,
+            Channel::Fan => 0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pump => "pump",
+            Channel::Fan => "fan",
+        }
+    }
+
+    /// check duty is a percentage, and raise it to the safety floor if needed
+    pub fn validate(&self, duty: u8) -> Result<u8, KrakenError> {
+        if duty > 100 {
+            return Err(KrakenError::Invalid(format!(
+                "{} duty needs to be between {} and 100",
+                self.name(),
+                self.min_duty()
+            )));
+        }
+        Ok(duty.max(self.min_duty()))
+    }
+}
+
+/// Duty for a liquid temperature, interpolated linearly between points
+#[derive(Clone, Debug, PartialEq)]
+pub struct Curve {
+    points: Vec<(f32, u8)>,
+}
+
+impl Curve {
+    /// parse from the config format temp:duty,temp:duty e.g. 25:30,40:60,50:100
+    pub fn parse(string: &str) -> Result<Curve, String> {
+        let mut points = vec![];
+        for point in string
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+        {
+            let (temp, duty) = point
+                .split_once(':')
+                .ok_or(format!("curve point '{point}' should be temp:duty"))?;
+            let temp = temp
+                .trim()
+                .parse::<f32>()
+                .map_err(|_| format!("invalid temperature in '{point}'"))?;
+            let duty = duty
+                .trim()
+                .trim_end_matches('%')
+                .parse::<u8>()
+                .map_err(|_| format!("invalid duty in '{point}'"))?;
+            if duty > 100 {
+                return Err(format!("duty in '{point}' is over 100"));
+            }
+            points.push((temp, duty));
+        }
+        if points.is_empty() {
+            return Err("curve has no points".to_string());
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Curve { points })
+    }
+
+    /// duty at temp, holding the first and last duty outside the curve
+    pub fn duty_at(&self, temp: f32) -> u8 {
+        let first = self.points[0];
+        let last = self.points[self.points.len() - 1];
+        if temp <= first.0 {
+            return first.1;
+        }
+        if temp >= last.0 {
+            return last.1;
+        }
+        for pair in self.points.windows(2) {
+            let ((t0, d0), (t1, d1)) = (pair[0], pair[1]);
+            if temp <= t1 {
+                let ratio = (temp - t0) / (t1 - t0);
+                return (d0 as f32 + ratio * (d1 as f32 - d0 as f32)).round() as u8;
+            }
+        }
+        last.1
+    }
+
+    /// device profile for the channel, with the channel's safety floor applied
+    pub fn profile(&self, channel: Channel) -> [u8; PROFILE_POINTS] {
+        let mut duties = [0u8; PROFILE_POINTS];
+        for (i, duty) in duties.iter_mut().enumerate() {
+            let temp = (PROFILE_START_TEMP as usize + i) as f32;
+            *duty = self.duty_at(temp).max(channel.min_duty());
+        }
+        duties
+    }
+}
+
+impl Display for Curve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let points = self
+            .points
+            .iter()
+            .map(|(t, d)| format!("{t}:{d}"))
+            .collect::<Vec<String>>()
+            .join(",");
+        write!(f, "{points}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duty_at_interpolates_between_points() {
+        let curve = Curve::parse("25:30,40:60,50:100").unwrap();
+        assert_eq!(curve.duty_at(25.), 30);
+        assert_eq!(curve.duty_at(32.5), 45);
+        assert_eq!(curve.duty_at(40.), 60);
+        assert_eq!(curve.duty_at(41.), 64);
+        assert_eq!(curve.duty_at(50.), 100);
+    }
+
+    #[test]
+    fn duty_at_holds_ends_outside_curve() {
+        let curve = Curve::parse("40:60, 25:30").unwrap();
+        assert_eq!(curve.duty_at(-5.), 30);
+        assert_eq!(curve.duty_at(20.), 30);
+        assert_eq!(curve.duty_at(80.), 60);
+    }
+
+    #[test]
+    fn duty_at_single_point_is_flat() {
+        let curve = Curve::parse("30:50%").unwrap();
+        assert_eq!(curve.duty_at(0.), 50);
+        assert_eq!(curve.duty_at(60.), 50);
+    }
+
+    #[test]
+    fn profile_applies_pump_floor() {
+        let curve = Curve::parse("20:0,59:100").unwrap();
+        let pump = curve.profile(Channel::Pump);
+        let fan = curve.profile(Channel::Fan);
+        assert_eq!(pump[0], 20);
+        assert_eq!(fan[0], 0);
+        assert_eq!(pump[PROFILE_POINTS - 1], 100);
+        assert_eq!(fan[PROFILE_POINTS - 1], 100);
+    }
+
+    #[test]
+    fn invalid_curves_are_rejected() {
+        assert!(Curve::parse("").is_err());
+        assert!(Curve::parse("30").is_err());
+        assert!(Curve::parse("hot:50").is_err());
+        assert!(Curve::parse("30:101").is_err());
+    }
+}
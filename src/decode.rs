@@ -0,0 +1,187 @@
+use crate::{
+    capture,
+    error::KrakenError,
+    imagetools,
+    protocol::BULK_MAGIC,
+    record::{self, Direction, Event},
+    transport::Kind,
+};
+use std::path::{Path, PathBuf};
+
+/// Events from a krakenctl recording, or a usbmon or USBPcap capture
+pub fn load(path: &Path) -> Result<Vec<Event>, KrakenError> {
+    match record::load(path) {
+        Err(KrakenError::Invalid(_)) => capture::load(path),
+        result => result,
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    //interrupt packets are padded with zeros to 64 bytes
+    let end = data.len() - data.iter().rev().take_while(|&&b| b == 0).count();
+    data[..end]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn u16_le(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+/// Known commands and replies in words, anything else as hex
+pub fn describe(event: &Event) -> String {
+    let data = &event.data;
+    if event.kind == Kind::Bulk {
+        return match data.get(..12) {
+            Some(magic) if magic == BULK_MAGIC && data.len() >= 20 => format!(
+                "bulk header {} of {} bytes",
+                match data[12] {
+                    1 => "gif",
+                    2 => "rgba",
+                    _ => "unknown type",
+                },
+                u32::from_le_bytes([data[16], data[17], data[18], data[19]])
+            ),
+            _ => format!("bulk data {} bytes", data.len()),
+        };
+    }
+    let padded = |len: usize| {
+        let mut padded = data.clone();
+        padded.resize(padded.len().max(len), 0);
+        padded
+    };
+    let d = padded(64);
+    match (d[0], d[1]) {
+        (0x10, 0x01) => "firmware request".to_string(),
+        (0x11, 0x01) => format!("firmware {}.{}.{}", d[17], d[18], d[19]),
+        (0x30, 0x01) => "lcd info request".to_string(),
+        (0x31, 0x01) => format!(
+            "lcd info brightness {} orientation {}°",
+            d[24],
+            d[26] as u16 % 4 * 90
+        ),
+        (0x30, 0x02) => format!(
+            "set brightness {} orientation {}°",
+            d[3],
+            d[7] as u16 % 4 * 90
+        ),
+        (0x30, 0x04) => format!("clear bucket {}", d[2]),
+        (0x32, 0x01) => format!(
+            "setup bucket {} start block {} size {} blocks",
+            d[2],
+            u16_le(&d, 4),
+            u16_le(&d, 6)
+        ),
+        (0x32, 0x02) => format!("delete bucket {}", d[2]),
+        (0x33, sub) => format!("bucket reply {sub:#04x} {}", hex(&d[2..])),
+        (0x36, 0x01) => format!("bulk start bucket {}", d[2]),
+        (0x36, 0x02) => "bulk end".to_string(),
+        (0x37, sub) => format!("bulk reply {sub:#04x} {}", hex(&d[2..])),
+        (0x38, 0x01) => match d[2] {
+            0 | 1 => "show blank screen".to_string(),
+            2 => "show liquid screen".to_string(),
+            4 => format!("show bucket {}", d[3]),
+            mode => format!("switch to mode {mode}"),
+        },
+        (0x74, 0x01) => "status request".to_string(),
+        (0x75, 0x01) => format!(
+            "status liquid {}.{}°C pump {}rpm {}% fan {}rpm {}%",
+            d[15],
+            d[16],
+            u16_le(&d, 17),
+            d[19],
+            u16_le(&d, 23),
+            d[25]
+        ),
+        (0x72, channel) => format!(
+            "{} speed profile {}",
+            match channel {
+                1 => "pump",
+                2 => "fan",
+                _ => "unknown",
+            },
+            hex(&d[4..44])
+        ),
+        (0x2a, 0x04) => format!("lighting channel {:#05b} mode {:#04x}", d[2], d[4]),
+        (0x22, sub) => format!("per led lighting {sub:#04x} channel {:#05b}", d[2]),
+        _ => hex(data),
+    }
+}
+
+/// An upload in progress, from its bulk header until all of its bytes arrived
+struct Upload {
+    is_gif: bool,
+    size: usize,
+    data: Vec<u8>,
+}
+
+/// Print every event, and save each uploaded image into dir, named after the capture.
+/// Returns the saved files
+pub fn decode(events: &[Event], dir: &Path, name: &str) -> Result<Vec<PathBuf>, KrakenError> {
+    let mut upload: Option<Upload> = None;
+    let mut saved = vec![];
+    for event in events {
+        println!(
+            "{:>10.6} {} {:<9} ep {:#04x} {}",
+            event.time.as_secs_f64(),
+            match event.direction {
+                Direction::Out => "->",
+                Direction::In => "<-",
+            },
+            format!("{:?}", event.kind).to_lowercase(),
+            event.endpoint,
+            describe(event)
+        );
+        if event.kind != Kind::Bulk || event.direction != Direction::Out {
+            continue;
+        }
+        let data = &event.data;
+        if data.len() >= 20 && data[..12] == BULK_MAGIC {
+            upload = Some(Upload {
+                is_gif: data[12] == 1,
+                size: u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize,
+                data: vec![],
+            });
+            continue;
+        }
+        let complete = match upload.as_mut() {
+            Some(current) => {
+                current.data.extend_from_slice(data);
+                current.data.len() >= current.size
+            }
+            None => continue,
+        };
+        let Some(Upload {
+            is_gif,
+            size,
+            mut data,
+        }) = upload.take_if(|_| complete)
+        else {
+            continue;
+        };
+        data.truncate(size);
+        std::fs::create_dir_all(dir).map_err(|e| KrakenError::file(dir, e))?;
+        let path = dir.join(format!(
+            "{name}-{:03}.{}",
+            saved.len() + 1,
+            if is_gif { "gif" } else { "png" }
+        ));
+        if is_gif {
+            std::fs::write(&path, data).map_err(|e| KrakenError::file(dir, e))?;
+        } else {
+            let Some(image) = imagetools::frame_to_image(data) else {
+                eprintln!("Skipping upload of {size} bytes, not a square rgba frame");
+                continue;
+            };
+            image.save(&path).map_err(|e| KrakenError::file(dir, e))?;
+        }
+        println!("saved {}", path.display());
+        saved.push(path);
+    }
+    if upload.is_some() {
+        eprintln!("Last upload is incomplete, the capture may be truncated");
+    }
+    Ok(saved)
+}
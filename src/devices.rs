@@ -0,0 +1,98 @@
+pub const NZXT_VID: u16 = 0x1e71;
+
+//device memory is addressed in blocks of 1KiB
+pub const BLOCK_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    Brightness,
+    Status,
+    Firmware,
+    Liquid,
+    Blank,
+    Image,
+    Gif,
+    Cooling,
+    Lighting,
+    Orientation,
+}
+
+/// Describes one model of Kraken with an LCD
+#[derive(Debug, PartialEq)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    pub vid: u16,
+    pub pid: u16,
+    pub width: u32,
+    pub height: u32,
+    pub bucket_count: u8,
+    /// total image memory in blocks
+    pub memory_blocks: u16,
+    pub features: &'static [Feature],
+}
+
+const ALL_FEATURES: &[Feature] = &[
+    Feature::Brightness,
+    Feature::Status,
+    Feature::Firmware,
+    Feature::Liquid,
+    Feature::Blank,
+    Feature::Image,
+    Feature::Gif,
+    Feature::Cooling,
+    Feature::Lighting,
+    Feature::Orientation,
+];
+
+pub static DEVICES: &[DeviceProfile] = &[
+    DeviceProfile {
+        name: "Kraken Z53/Z63/Z73",
+        vid: NZXT_VID,
+        pid: 0x3008,
+        width: 320,
+        height: 320,
+        bucket_count: 16,
+        memory_blocks: 24320,
+        features: ALL_FEATURES,
+    },
+    DeviceProfile {
+        name: "Kraken 2023",
+        vid: NZXT_VID,
+        pid: 0x300e,
+        width: 240,
+        height: 240,
+        bucket_count: 16,
+        memory_blocks: 24320,
+        features: ALL_FEATURES,
+    },
+    DeviceProfile {
+        name: "Kraken Elite",
+        vid: NZXT_VID,
+        pid: 0x300c,
+        width: 640,
+        height: 640,
+        bucket_count: 16,
+        memory_blocks: 24320,
+        features: ALL_FEATURES,
+    },
+];
+
+pub fn find(vid: u16, pid: u16) -> Option<&'static DeviceProfile> {
+    DEVICES.iter().find(|d| d.vid == vid && d.pid == pid)
+}
+
+/// The original Z3 profile, used when no device is attached (e.g. with the mock)
+pub fn default_profile() -> &'static DeviceProfile {
+    &DEVICES[0]
+}
+
+impl DeviceProfile {
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    /// size in bytes of one full RGBA frame
+    pub fn frame_size(&self) -> usize {
+        self.width as usize * self.height as usize * 4
+    }
+}
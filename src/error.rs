@@ -0,0 +1,76 @@
+use std::{fmt::Display, path::Path};
+
+#[derive(Debug)]
+pub enum KrakenError {
+    NotFound,
+    PermissionDenied,
+    Busy,
+    Timeout,
+    /// device does not support the command
+    Unsupported(String),
+    /// bad value passed in
+    Invalid(String),
+    /// device replied with something unexpected
+    Protocol(String),
+    ImageDecode(String),
+    File(String),
+    /// any other usb error
+    Usb(rusb::Error),
+}
+
+impl KrakenError {
+    /// error reading or writing path, with the cause
+    pub fn file(path: impl AsRef<Path>, e: impl Display) -> Self {
+        KrakenError::File(format!("{}: {e}", path.as_ref().display()))
+    }
+
+    /// exit code for the process, so scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KrakenError::NotFound => 2,
+            KrakenError::PermissionDenied => 3,
+            KrakenError::Busy => 4,
+            KrakenError::Timeout => 5,
+            KrakenError::Protocol(_) => 6,
+            KrakenError::ImageDecode(_) => 7,
+            KrakenError::File(_) => 8,
+            KrakenError::Usb(_) => 9,
+            KrakenError::Unsupported(_) => 10,
+            KrakenError::Invalid(_) => 11,
+        }
+    }
+}
+
+impl Display for KrakenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KrakenError::NotFound => write!(f, "Could not find a supported kraken device"),
+            KrakenError::PermissionDenied => write!(
+                f,
+                "Permission denied opening kraken, run as root or add a udev rule"
+            ),
+            KrakenError::Busy => write!(f, "Kraken is busy, is another program using it?"),
+            KrakenError::Timeout => write!(f, "Timed out talking to kraken"),
+            KrakenError::Protocol(e) => write!(f, "Unexpected reply from kraken: {e}"),
+            KrakenError::ImageDecode(e) => write!(f, "Could not decode image: {e}"),
+            KrakenError::File(e) => write!(f, "Could not access file: {e}"),
+            KrakenError::Usb(e) => write!(f, "USB error: {e}"),
+            KrakenError::Unsupported(e) => write!(f, "Not supported: {e}"),
+            KrakenError::Invalid(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for KrakenError {}
+
+impl From<rusb::Error> for KrakenError {
+    fn from(value: rusb::Error) -> Self {
+        match value {
+            rusb::Error::NoDevice | rusb::Error::NotFound => KrakenError::NotFound,
+            rusb::Error::Access => KrakenError::PermissionDenied,
+            rusb::Error::Busy => KrakenError::Busy,
+            rusb::Error::Timeout => KrakenError::Timeout,
+            e => KrakenError::Usb(e),
+        }
+    }
+}
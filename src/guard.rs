@@ -0,0 +1,168 @@
+use crate::{
+    error::KrakenError,
+    managerrusb::DebugLevel,
+    transport::{self, DeviceInfo},
+};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+
+/// The device with kernel drivers detached by this process
+struct Active {
+    bus: u8,
+    port_path: Vec<u8>,
+    drivers: Vec<u8>,
+    debug_level: DebugLevel,
+}
+
+static ACTIVE: Mutex<Option<Active>> = Mutex::new(None);
+
+//set while a repeat loop handles exit signals itself
+static GRACEFUL: AtomicBool = AtomicBool::new(false);
+
+fn debug(string: impl ToString, debug_level: DebugLevel, level: DebugLevel) {
+    if debug_level >= level {
+        eprintln!("{}", string.to_string());
+    }
+}
+
+/// Remember the device whose drivers were detached, so they can be restored
+/// if krakenctl stops without dropping the transport
+pub fn register(info: &DeviceInfo, drivers: &[u8], debug_level: DebugLevel) {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    *active = Some(Active {
+        bus: info.bus,
+        port_path: info.port_path.clone(),
+        drivers: drivers.to_vec(),
+        debug_level,
+    });
+}
+
+/// The transport cleaned up after itself, nothing left to restore
+pub fn clear() {
+    ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Reset the registered device and reattach its kernel drivers, if there is one.
+/// Opens a new handle, as the session's handle may be in use or gone
+pub fn restore() {
+    let Some(active) = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let level = active.debug_level;
+    let handle = match transport::open_at(active.bus, &active.port_path, level) {
+        Ok(handle) => handle,
+        Err(e) => {
+            debug(
+                format!("Could not open device to restore drivers {e}"),
+                level,
+                DebugLevel::Error,
+            );
+            return;
+        }
+    };
+    //a reset drops the claims this process still holds
+    if handle.reset().is_err() {
+        debug("Could not reset device", level, DebugLevel::Error);
+    }
+    for &i in active.drivers.iter() {
+        match handle.attach_kernel_driver(i) {
+            Ok(()) => debug(format!("kernel attach:{i:?}"), level, DebugLevel::Debug),
+            Err(e) => debug(
+                format!("could not attach kernel driver {i}: {e}"),
+                level,
+                DebugLevel::Error,
+            ),
+        }
+    }
+    if handle.reset().is_err() {
+        debug("Could not reset device", level, DebugLevel::Error);
+    }
+}
+
+/// Restore the device before exiting, as destructors do not run on exit
+pub fn exit(code: i32) -> ! {
+    restore();
+    std::process::exit(code)
+}
+
+/// While set, the first exit signal is left for the repeat loop to finish cleanly
+pub fn set_graceful(graceful: bool) {
+    GRACEFUL.store(graceful, Ordering::Relaxed);
+}
+
+/// Restore the device on panic and on fatal signals
+pub fn install() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        restore();
+    }));
+
+    #[cfg(target_os = "linux")]
+    {
+        use signal_hook::consts::signal::*;
+        use signal_hook::iterator::Signals;
+        //every signal that ends the process by default and can be caught.
+        //faults such as SIGSEGV cannot be handled here, the panic hook covers crashes in rust
+        let fatal = [
+            SIGINT, SIGTERM, SIGHUP, SIGQUIT, SIGUSR1, SIGUSR2, SIGALRM, SIGVTALRM, SIGPROF,
+            SIGXCPU, SIGXFSZ, SIGIO,
+        ];
+        let Ok(mut signals) = Signals::new(fatal) else {
+            eprintln!("Could not register signal handlers, drivers may not be restored");
+            return;
+        };
+        std::thread::spawn(move || {
+            let mut pending = false;
+            for signal in signals.forever() {
+                //give the loop a chance, but a second signal forces the exit
+                if GRACEFUL.load(Ordering::Relaxed) && !pending && signal != SIGQUIT {
+                    pending = true;
+                    continue;
+                }
+                eprintln!("Got signal {signal}, restoring device");
+                exit(128 + signal);
+            }
+        });
+    }
+}
+
+/// Reattach kernel drivers left detached by a run that crashed or was killed
+pub fn recover(debug_level: DebugLevel) -> Result<(), KrakenError> {
+    let devices = transport::list_devices()?;
+    if devices.is_empty() {
+        return Err(KrakenError::NotFound);
+    }
+    for info in devices {
+        let handle = transport::open_at(info.bus, &info.port_path, debug_level)?;
+        handle.reset()?;
+        if cfg!(target_os = "linux") {
+            for i in [0, 1] {
+                if handle.kernel_driver_active(i).unwrap_or_default() {
+                    debug(
+                        format!("kernel driver already active:{i}"),
+                        debug_level,
+                        DebugLevel::Info,
+                    );
+                    continue;
+                }
+                //not every interface has a kernel driver to go back to
+                match handle.attach_kernel_driver(i) {
+                    Ok(()) => {
+                        println!("{}: reattached driver on interface {i}", info.port_string())
+                    }
+                    Err(e) => debug(
+                        format!("no kernel driver for interface {i}: {e}"),
+                        debug_level,
+                        DebugLevel::Info,
+                    ),
+                }
+            }
+            handle.reset()?;
+        }
+        println!("{}: {} recovered", info.port_string(), info.profile.name);
+    }
+    Ok(())
+}
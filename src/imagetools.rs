@@ -3,29 +3,112 @@ use chrono::{Local, Timelike};
 use std::f32::consts::PI;
 use unicode_segmentation::UnicodeSegmentation;
 
-use image::{DynamicImage, ImageReader, Rgba};
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    imageops, AnimationDecoder, DynamicImage, Frame, ImageError, ImageReader, Rgba, RgbaImage,
+};
 use imageproc::{
     drawing::{draw_filled_circle_mut, draw_polygon_mut, draw_text_mut},
     point::Point,
 };
+use std::io::Cursor;
 
-use crate::{input::Input, settings::Settings};
+use crate::{error::KrakenError, input::Input, settings::Settings};
 
 static FONT_DATA: &[u8] = include_bytes!("../JetbrainsMonoBold.ttf");
 
-pub fn convert_image_from_path(path: &str) -> DynamicImage {
-    let img = ImageReader::open(path).unwrap().decode().unwrap();
+//layouts are designed for a 320x320 lcd and scaled to the native size
+const DESIGN_SIZE: f32 = 320.0;
+
+//scale a coordinate from the 320 design to the native size
+fn px(val: f32, size: u32) -> i32 {
+    (val * size as f32 / DESIGN_SIZE) as i32
+}
+
+fn scaled(val: f32, size: u32) -> f32 {
+    val * size as f32 / DESIGN_SIZE
+}
+
+pub fn convert_image_from_path(path: &str, size: u32) -> Result<DynamicImage, KrakenError> {
+    let img = ImageReader::open(path)
+        .map_err(|e| KrakenError::file(path, e))?
+        .decode()
+        .map_err(|e| KrakenError::ImageDecode(format!("{path}: {e}")))?;
 
     //scale and flip
-    let scale = f32::max(320.0 / img.width() as f32, 320.0 / img.height() as f32);
+    let side = size as f32;
+    let scale = f32::max(side / img.width() as f32, side / img.height() as f32);
     let img2 = img.resize(
         f32::ceil(img.width() as f32 * scale) as u32,
         f32::ceil(img.height() as f32 * scale) as u32,
         image::imageops::FilterType::Lanczos3,
     );
-    let img3 = img2.crop_imm(0, 0, 320, 320);
+    let img3 = img2.crop_imm(0, 0, size, size);
+
+    Ok(img3)
+}
+
+/// Rotate a frame clockwise by orientation degrees, then into the lcd's raw layout
+/// which is upside down
+pub fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    let image = match orientation {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image,
+    };
+    image.rotate180()
+}
+
+/// Turn a square frame in the lcd's raw layout back into an upright image
+pub fn frame_to_image(frame: Vec<u8>) -> Option<DynamicImage> {
+    let side = ((frame.len() / 4) as f64).sqrt() as u32;
+    let image = RgbaImage::from_raw(side, side, frame)?;
+    Some(DynamicImage::ImageRgba8(image).rotate180())
+}
+
+/// Make everything outside the round lcd transparent
+pub fn circle_mask(image: &mut RgbaImage) {
+    let radius = image.width().min(image.height()) as f32 / 2.0;
+    let (cx, cy) = (image.width() as f32 / 2.0, image.height() as f32 / 2.0);
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        if dx * dx + dy * dy > radius * radius {
+            pixel[3] = 0;
+        }
+    }
+}
+
+/// Rotate every frame of a gif clockwise by orientation degrees.
+/// The device decodes gifs itself, so they are not put in the raw layout
+pub fn rotate_gif(bytes: &[u8], orientation: u16) -> Result<Vec<u8>, KrakenError> {
+    if orientation == 0 {
+        return Ok(bytes.to_vec());
+    }
+    let decode_error = |e: ImageError| KrakenError::ImageDecode(format!("gif: {e}"));
+    let frames = GifDecoder::new(Cursor::new(bytes))
+        .map_err(decode_error)?
+        .into_frames()
+        .collect_frames()
+        .map_err(decode_error)?;
 
-    img3.flipv()
+    let mut out = vec![];
+    {
+        let mut encoder = GifEncoder::new(&mut out);
+        encoder.set_repeat(Repeat::Infinite).map_err(decode_error)?;
+        for frame in frames {
+            let delay = frame.delay();
+            let buffer = match orientation {
+                90 => imageops::rotate90(frame.buffer()),
+                180 => imageops::rotate180(frame.buffer()),
+                _ => imageops::rotate270(frame.buffer()),
+            };
+            encoder
+                .encode_frame(Frame::from_parts(buffer, 0, 0, delay))
+                .map_err(decode_error)?;
+        }
+    }
+    Ok(out)
 }
 
 //pub fn convert_gif_from_path(path: &str) -> Vec<u8> {
@@ -42,8 +125,8 @@ pub fn convert_image_from_path(path: &str) -> DynamicImage {
 //    decoder.
 //}
 
-pub fn image_from_input(input: Input, settings: &Settings) -> Vec<u8> {
-    let mut image = DynamicImage::new_rgba8(320, 320);
+pub fn image_from_input(input: Input, settings: &Settings, size: u32, orientation: u16) -> Vec<u8> {
+    let mut image = DynamicImage::new_rgba8(size, size);
 
     if input.values.len() == 1 {
         draw_bars(
@@ -52,18 +135,21 @@ pub fn image_from_input(input: Input, settings: &Settings) -> Vec<u8> {
             input.values[0],
             settings.left_bar,
             settings.right_bar,
+            size,
         );
         draw_value(
             &mut image,
             &[&input.get_string_at(0)],
             settings.left_value,
             settings.right_value,
+            size,
         );
         draw_title(
             &mut image,
             &[&input.get_title_at(0)],
             settings.left_title,
             settings.right_title,
+            size,
         );
     } else if input.values.len() >= 2 {
         draw_bars(
@@ -72,30 +158,31 @@ pub fn image_from_input(input: Input, settings: &Settings) -> Vec<u8> {
             input.values[1],
             settings.left_bar,
             settings.right_bar,
+            size,
         );
         draw_value(
             &mut image,
             &[&input.get_string_at(0), &input.get_string_at(1)],
             settings.left_value,
             settings.right_value,
+            size,
         );
         draw_title(
             &mut image,
             &[&input.get_title_at(0), &input.get_title_at(1)],
             settings.left_title,
             settings.right_title,
+            size,
         );
     }
     if input.time || settings.show_time {
-        draw_time(&mut image, settings.time);
+        draw_time(&mut image, settings.time, size);
     }
 
     //test:
     //draw_title(&mut image,&[&input.overlay],settings.left_title,settings.left_title);
 
-    let image = image.fliph();
-    let image = image.flipv();
-    //image.save("/tmp/test.png").unwrap();
+    let image = orient(image, orientation);
     image.into_bytes()
 }
 
@@ -105,17 +192,18 @@ fn draw_bars(
     right_val: f32,
     left_col: Rgba<u8>,
     right_col: Rgba<u8>,
+    size: u32,
 ) {
-    let width = 33.0;
+    let width = scaled(33.0, size);
     let black = Rgba([0, 0, 0, 255]);
     let grey = Rgba([30, 30, 30, 255]);
+    let c = size as f32 / 2.0; //centre
+    let ci = c as i32;
     //normal range: 0-80
-    let cr = (320.0 - width) / 2.0; //between outer 320 and inner 240
-    let tr = 1520.0 / 2.0;
+    let cr = (size as f32 - width) / 2.0; //between outer 320 and inner 240
+    let tr = scaled(1520.0, size) / 2.0;
     //left
-    let left_ratio = ((left_val.min(100.) as f32 - 0.0) / 120.0)
-        .max(0.0)
-        .min(1.0);
+    let left_ratio = (left_val.min(100.) / 120.0).clamp(0.0, 1.0);
     let left_theta = left_ratio * PI / 2.0;
     let lcw = cr * left_theta.cos();
     let lch = cr * left_theta.sin();
@@ -123,9 +211,7 @@ fn draw_bars(
     let lth = tr * left_theta.sin();
 
     //right
-    let right_ratio = ((right_val.min(100.) as f32 - 0.0) / 120.0)
-        .max(0.0)
-        .min(1.0);
+    let right_ratio = (right_val.min(100.) / 120.0).clamp(0.0, 1.0);
     let right_theta = right_ratio * PI / 2.0;
     let rcw = cr * right_theta.cos();
     let rch = cr * right_theta.sin();
@@ -133,19 +219,19 @@ fn draw_bars(
     let rth = tr * right_theta.sin();
 
     //outer loop
-    draw_filled_circle_mut(image, (160, 160), 160, grey);
+    draw_filled_circle_mut(image, (ci, ci), ci, grey);
 
     draw_polygon_mut(
         image,
         &[
-            Point { x: 160, y: 160 },
+            Point { x: ci, y: ci },
             Point {
-                x: 160 - ltw as i32,
-                y: 160 - lth as i32,
+                x: ci - ltw as i32,
+                y: ci - lth as i32,
             },
             Point {
-                x: 160 - ltw as i32,
-                y: 160 + lth as i32,
+                x: ci - ltw as i32,
+                y: ci + lth as i32,
             },
         ],
         left_col,
@@ -154,98 +240,111 @@ fn draw_bars(
     draw_polygon_mut(
         image,
         &[
-            Point { x: 160, y: 160 },
+            Point { x: ci, y: ci },
             Point {
-                x: 160 + rtw as i32,
-                y: 160 - rth as i32,
+                x: ci + rtw as i32,
+                y: ci - rth as i32,
             },
             Point {
-                x: 160 + rtw as i32,
-                y: 160 + rth as i32,
+                x: ci + rtw as i32,
+                y: ci + rth as i32,
             },
         ],
         right_col,
     );
 
-    draw_filled_circle_mut(image, (160, 160), 160 - width as i32, black);
+    draw_filled_circle_mut(image, (ci, ci), ci - width as i32, black);
 
     //ends
     draw_filled_circle_mut(
         image,
-        ((160.0 - lcw) as i32, (160.0 - lch) as i32),
+        ((c - lcw) as i32, (c - lch) as i32),
         (width / 2.0) as i32,
         left_col,
     );
     draw_filled_circle_mut(
         image,
-        ((160.0 - lcw) as i32, (160.0 + lch) as i32),
+        ((c - lcw) as i32, (c + lch) as i32),
         (width / 2.0) as i32,
         left_col,
     );
     draw_filled_circle_mut(
         image,
-        ((160.0 + rcw) as i32, (160.0 - rch) as i32),
+        ((c + rcw) as i32, (c - rch) as i32),
         (width / 2.0) as i32,
         right_col,
     );
     draw_filled_circle_mut(
         image,
-        ((160.0 + rcw) as i32, (160.0 + rch) as i32),
+        ((c + rcw) as i32, (c + rch) as i32),
         (width / 2.0) as i32,
         right_col,
     );
 }
 
-fn draw_time(image: &mut DynamicImage, col: Rgba<u8>) {
+fn draw_time(image: &mut DynamicImage, col: Rgba<u8>, size: u32) {
     let font = FontRef::try_from_slice(FONT_DATA).expect("Error constructing Font");
 
-    let scale = 50.0;
+    let scale = scaled(50.0, size);
     let ch = Local::now();
     let val = format!("{}:{:0>2}", ch.time().hour(), ch.time().minute());
-    let x: i32 = 160 - get_width(&val, &font, scale) as i32 / 2;
-    draw_text_mut(image, col, x, 45, scale, &font, &val);
+    let x: i32 = px(160.0, size) - get_width(&val, &font, scale) / 2;
+    draw_text_mut(image, col, x, px(45.0, size), scale, &font, &val);
 }
-fn draw_value(image: &mut DynamicImage, vals: &[&str], left_col: Rgba<u8>, right_col: Rgba<u8>) {
+fn draw_value(
+    image: &mut DynamicImage,
+    vals: &[&str],
+    left_col: Rgba<u8>,
+    right_col: Rgba<u8>,
+    size: u32,
+) {
     let font = FontRef::try_from_slice(FONT_DATA).expect("Error constructing Font");
 
     if vals.len() == 1 {
-        let scale = 80.0;
+        let scale = scaled(80.0, size);
         let val = truncate(vals[0], 6); //max 6
         let width = get_width(&val, &font, scale);
-        let x: i32 = 160 - width / 2;
+        let x: i32 = px(160.0, size) - width / 2;
 
-        draw_text_mut(image, left_col, x, 110, scale, &font, &val);
+        draw_text_mut(image, left_col, x, px(110.0, size), scale, &font, &val);
     } else if vals.len() >= 2 {
-        let scale = 65.0;
+        let scale = scaled(65.0, size);
         let val0 = truncate(vals[0], 4); //max 6
         let val1 = truncate(vals[1], 4);
         let width0 = get_width(&val0, &font, scale);
         let width1 = get_width(&val1, &font, scale);
-        let x0 = 105 - width0 / 2;
-        let x1 = 215 - width1 / 2;
-        draw_text_mut(image, left_col, x0, 120, scale, &font, &val0);
-        draw_text_mut(image, right_col, x1, 120, scale, &font, &val1);
+        let x0 = px(105.0, size) - width0 / 2;
+        let x1 = px(215.0, size) - width1 / 2;
+        let y = px(120.0, size);
+        draw_text_mut(image, left_col, x0, y, scale, &font, &val0);
+        draw_text_mut(image, right_col, x1, y, scale, &font, &val1);
     }
 }
 
-fn draw_title(image: &mut DynamicImage, vals: &[&str], left_col: Rgba<u8>, right_col: Rgba<u8>) {
+fn draw_title(
+    image: &mut DynamicImage,
+    vals: &[&str],
+    left_col: Rgba<u8>,
+    right_col: Rgba<u8>,
+    size: u32,
+) {
     let font = FontRef::try_from_slice(FONT_DATA).expect("Error constructing Font");
 
+    let scale = scaled(40.0, size);
+    let y = px(190.0, size);
     if vals.len() == 1 {
-        let scale = 40.0;
         let val = truncate(vals[0], 6); //max 6
-        let x = 160 - get_width(&val, &font, scale) / 2;
-        draw_text_mut(image, left_col, x, 190, scale, &font, &val);
+        let x = px(160.0, size) - get_width(&val, &font, scale) / 2;
+        draw_text_mut(image, left_col, x, y, scale, &font, &val);
     } else if vals.len() >= 2 {
-        let scale = 40.0;
         let val0 = truncate(vals[0], 4); //max 6
         let val1 = truncate(vals[1], 4);
         let width0 = get_width(&val0, &font, scale);
         let width1 = get_width(&val1, &font, scale);
-        let x0 = 105 - width0 / 2;
-        let x1 = 215 - width1 / 2;
-        draw_text_mut(image, left_col, x0, 190, scale, &font, &val0);
-        draw_text_mut(image, right_col, x1, 190, scale, &font, &val1);
+        let x0 = px(105.0, size) - width0 / 2;
+        let x1 = px(215.0, size) - width1 / 2;
+        draw_text_mut(image, left_col, x0, y, scale, &font, &val0);
+        draw_text_mut(image, right_col, x1, y, scale, &font, &val1);
     }
 }
 
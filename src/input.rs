@@ -26,10 +26,10 @@ impl Input {
         if !lines.is_empty() {
             let vals = lines[0].split(',').collect::<Vec<&str>>();
 
+            //split by text to get pre/postfixes.
+            //assumes numbers to be congruent with each other
+            let re = Regex::new(r"(.*?)(\-?[0-9]+\.?[0-9]*)(.*)").expect("invalid regex");
             for v in vals {
-                //split by text to get pre/postfixes.
-                //assumes numbers to be congruent with each other
-                let re = Regex::new(r"(.*?)(\-?[0-9]+\.?[0-9]*)(.*)").expect("invalid regex");
                 let matches = re.captures(v);
                 if let Some(mat) = matches {
                     let pre = &mat[1];
@@ -46,7 +46,10 @@ impl Input {
         }
         //titles
         if lines.len() >= 2 {
-            let titles = lines[1].split(',').map(|x| x.to_owned()).collect::<Vec<String>>();
+            let titles = lines[1]
+                .split(',')
+                .map(|x| x.to_owned())
+                .collect::<Vec<String>>();
             s.titles.splice(.., titles);
         }
 
@@ -0,0 +1,325 @@
+use crate::{error::KrakenError, settings::parse_rgba};
+use image::Rgba;
+use std::str::FromStr;
+
+const MAX_COLORS: usize = 8;
+const MAX_LEDS: usize = 40;
+//colors that fit in one per led packet after the 4 header bytes
+const LEDS_PER_PACKET: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightChannel {
+    External,
+    Ring,
+    Logo,
+    Sync,
+}
+
+impl LightChannel {
+    pub fn id(&self) -> u8 {
+        match self {
+            LightChannel::External => 0b001,
+            LightChannel::Ring => 0b010,
+            LightChannel::Logo => 0b100,
+            LightChannel::Sync => 0b111,
+        }
+    }
+}
+
+impl FromStr for LightChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "external" => Ok(LightChannel::External),
+            "ring" => Ok(LightChannel::Ring),
+            "logo" => Ok(LightChannel::Logo),
+            "sync" => Ok(LightChannel::Sync),
+            _ => Err(format!(
+                "unknown channel '{s}', expected ring, logo, external or sync"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Off,
+    Fixed,
+    Breathing,
+    Fading,
+    Spectrum,
+    /// one color per led
+    PerLed,
+}
+
+impl Mode {
+    fn value(&self) -> u8 {
+        match self {
+            Mode::Off | Mode::Fixed => 0x00,
+            Mode::Fading | Mode::PerLed => 0x01,
+            Mode::Spectrum => 0x02,
+            Mode::Breathing => 0x06,
+        }
+    }
+
+    //allowed number of colors
+    fn color_range(&self) -> (usize, usize) {
+        match self {
+            Mode::Off | Mode::Spectrum => (0, 0),
+            Mode::Fixed => (1, 1),
+            Mode::Breathing => (1, MAX_COLORS),
+            Mode::Fading => (2, MAX_COLORS),
+            Mode::PerLed => (1, MAX_LEDS),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Mode::Off),
+            "fixed" | "static" => Ok(Mode::Fixed),
+            "breathing" => Ok(Mode::Breathing),
+            "fading" => Ok(Mode::Fading),
+            "spectrum" => Ok(Mode::Spectrum),
+            "per-led" => Ok(Mode::PerLed),
+            _ => Err(format!(
+                "unknown mode '{s}', expected off, fixed, breathing, fading, spectrum or per-led"
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Slowest,
+    Slower,
+    Normal,
+    Faster,
+    Fastest,
+}
+
+impl Speed {
+    fn value(&self) -> [u8; 2] {
+        match self {
+            Speed::Slowest => [0x50, 0x00],
+            Speed::Slower => [0x3c, 0x00],
+            Speed::Normal => [0x28, 0x00],
+            Speed::Faster => [0x14, 0x00],
+            Speed::Fastest => [0x0a, 0x00],
+        }
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "slowest" => Ok(Speed::Slowest),
+            "slower" => Ok(Speed::Slower),
+            "normal" => Ok(Speed::Normal),
+            "faster" => Ok(Speed::Faster),
+            "fastest" => Ok(Speed::Fastest),
+            _ => Err(format!(
+                "unknown speed '{s}', expected slowest, slower, normal, faster or fastest"
+            )),
+        }
+    }
+}
+
+/// An effect for one lighting channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lighting {
+    pub channel: LightChannel,
+    pub mode: Mode,
+    pub colors: Vec<Rgba<u8>>,
+    pub speed: Speed,
+}
+
+impl Lighting {
+    pub fn new(
+        channel: LightChannel,
+        mode: Mode,
+        colors: Vec<Rgba<u8>>,
+        speed: Speed,
+    ) -> Result<Self, KrakenError> {
+        let (min, max) = mode.color_range();
+        if colors.len() < min || colors.len() > max {
+            return Err(KrakenError::Invalid(format!(
+                "{mode:?} needs between {min} and {max} colors, got {}",
+                colors.len()
+            )));
+        }
+        Ok(Lighting {
+            channel,
+            mode,
+            colors,
+            speed,
+        })
+    }
+
+    /// colors separated by commas, each in the format #rrggbb
+    pub fn parse_colors(string: &str) -> Result<Vec<Rgba<u8>>, KrakenError> {
+        string
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                parse_rgba(x).ok_or_else(|| KrakenError::Invalid(format!("invalid colour '{x}'")))
+            })
+            .collect()
+    }
+
+    /// from the mode, colors and speed config entries of a channel
+    pub fn from_config(
+        channel: LightChannel,
+        mode: &str,
+        colors: Option<&str>,
+        speed: Option<&str>,
+    ) -> Result<Self, KrakenError> {
+        let mode = mode.parse::<Mode>().map_err(KrakenError::Invalid)?;
+        let colors = Lighting::parse_colors(colors.unwrap_or_default())?;
+        let speed = speed
+            .unwrap_or("normal")
+            .parse::<Speed>()
+            .map_err(KrakenError::Invalid)?;
+        Lighting::new(channel, mode, colors, speed)
+    }
+
+    //the device takes colors as green, red, blue
+    fn grb(colors: &[Rgba<u8>]) -> Vec<u8> {
+        colors.iter().flat_map(|c| [c[1], c[0], c[2]]).collect()
+    }
+
+    /// the 64 byte packets that apply this effect
+    pub fn packets(&self) -> Vec<Vec<u8>> {
+        let cid = self.channel.id();
+        let speed = self.speed.value();
+        let mut packets = vec![];
+        if self.mode == Mode::PerLed {
+            for (i, chunk) in self.colors.chunks(LEDS_PER_PACKET).enumerate() {
+                let mut packet = vec![0x22, 0x10 + i as u8, cid, 0x00];
+                packet.extend(Lighting::grb(chunk));
+                packets.push(packet);
+            }
+            let mut apply = vec![0x22, 0xa0, cid, 0x00, self.mode.value()];
+            apply.extend(speed);
+            apply.extend([0x08, 0x00, 0x00, 0x80, 0x00, 0x32, 0x00, 0x00, 0x01]);
+            packets.push(apply);
+        } else {
+            let mut packet = vec![0x2a, 0x04, cid, cid, self.mode.value()];
+            packet.extend(speed);
+            let mut colors = Lighting::grb(&self.colors);
+            colors.resize(MAX_COLORS * 2 * 3, 0);
+            packet.extend(colors);
+            //direction, color count, then led size
+            packet.extend([0x00, self.colors.len() as u8, 0x00, 0x00, 0x03]);
+            packets.push(packet);
+        }
+        for packet in packets.iter_mut() {
+            packet.resize(64, 0);
+        }
+        packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colors(string: &str) -> Vec<Rgba<u8>> {
+        Lighting::parse_colors(string).unwrap()
+    }
+
+    #[test]
+    fn effect_packet_has_grb_colors_padded_to_16() {
+        let lighting = Lighting::new(
+            LightChannel::Ring,
+            Mode::Fading,
+            colors("#ff0000, #00ff00,#102030"),
+            Speed::Faster,
+        )
+        .unwrap();
+        let packets = lighting.packets();
+        assert_eq!(packets.len(), 1);
+        let packet = &packets[0];
+        assert_eq!(packet.len(), 64);
+        assert_eq!(packet[..7], [0x2a, 0x04, 0b010, 0b010, 0x01, 0x14, 0x00]);
+        assert_eq!(
+            packet[7..16],
+            [0x00, 0xff, 0x00, 0xff, 0x00, 0x00, 0x20, 0x10, 0x30]
+        );
+        //unused color slots, 16 in all
+        assert!(packet[16..7 + 16 * 3].iter().all(|&b| b == 0));
+        //direction, color count, led size
+        assert_eq!(packet[55..60], [0x00, 3, 0x00, 0x00, 0x03]);
+        assert!(packet[60..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn off_has_no_colors() {
+        let lighting = Lighting::new(LightChannel::Sync, Mode::Off, vec![], Speed::Normal).unwrap();
+        let packet = &lighting.packets()[0];
+        assert_eq!(packet[..7], [0x2a, 0x04, 0b111, 0b111, 0x00, 0x28, 0x00]);
+        assert_eq!(packet[55..60], [0x00, 0, 0x00, 0x00, 0x03]);
+    }
+
+    #[test]
+    fn per_led_colors_are_chunked() {
+        let leds = (0..25)
+            .map(|i| format!("#{i:02x}0000"))
+            .collect::<Vec<String>>()
+            .join(",");
+        let lighting = Lighting::new(
+            LightChannel::Logo,
+            Mode::PerLed,
+            colors(&leds),
+            Speed::Normal,
+        )
+        .unwrap();
+        let packets = lighting.packets();
+        assert_eq!(packets.len(), 3);
+        assert!(packets.iter().all(|p| p.len() == 64));
+
+        assert_eq!(packets[0][..4], [0x22, 0x10, 0b100, 0x00]);
+        //green, red, blue of the first and last led in the packet
+        assert_eq!(packets[0][4..7], [0x00, 0x00, 0x00]);
+        assert_eq!(packets[0][61..64], [0x00, 19, 0x00]);
+
+        assert_eq!(packets[1][..4], [0x22, 0x11, 0b100, 0x00]);
+        assert_eq!(packets[1][4..7], [0x00, 20, 0x00]);
+        assert_eq!(packets[1][16..19], [0x00, 24, 0x00]);
+        assert!(packets[1][19..].iter().all(|&b| b == 0));
+
+        assert_eq!(
+            packets[2][..14],
+            [0x22, 0xa0, 0b100, 0x00, 0x01, 0x28, 0x00, 0x08, 0x00, 0x00, 0x80, 0x00, 0x32, 0x00]
+        );
+        assert_eq!(packets[2][14..16], [0x00, 0x01]);
+    }
+
+    #[test]
+    fn wrong_number_of_colors_is_rejected() {
+        fn new(mode: Mode, string: &str) -> Result<Lighting, KrakenError> {
+            Lighting::new(LightChannel::Ring, mode, colors(string), Speed::Normal)
+        }
+        assert!(new(Mode::Fixed, "").is_err());
+        assert!(new(Mode::Fixed, "#ff0000,#00ff00").is_err());
+        assert!(new(Mode::Fading, "#ff0000").is_err());
+        assert!(new(Mode::Spectrum, "#ff0000").is_err());
+        assert!(new(Mode::Breathing, &["#ffffff"; MAX_COLORS + 1].join(",")).is_err());
+        assert!(new(Mode::PerLed, &["#ffffff"; MAX_LEDS + 1].join(",")).is_err());
+        assert!(new(Mode::PerLed, &["#ffffff"; MAX_LEDS].join(",")).is_ok());
+    }
+
+    #[test]
+    fn invalid_colors_are_rejected() {
+        assert!(Lighting::parse_colors("#ff00").is_err());
+        assert!(Lighting::parse_colors("#ff0000,red").is_err());
+        assert_eq!(Lighting::parse_colors(" ").unwrap(), vec![]);
+    }
+}
@@ -1,27 +1,58 @@
 #![allow(dead_code)]
 #![allow(non_snake_case)]
 
+mod buckets;
+mod capture;
+mod cooling;
+mod decode;
+mod devices;
+mod error;
+mod guard;
 mod imagetools;
 mod input;
+mod lighting;
 mod managerrusb;
+#[cfg(test)]
+mod mock;
+mod protocol;
+mod record;
+mod report;
+mod schedule;
 mod settings;
+mod setup;
+mod simulator;
+mod transport;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use error::KrakenError;
+use lighting::{LightChannel, Lighting, Speed};
 use managerrusb::{DebugLevel, Manager};
+use report::Format;
 use settings::Settings;
 #[cfg(target_os = "linux")]
 use signal_hook::consts::{SIGHUP, SIGTSTP};
 use signal_hook::consts::{SIGINT, SIGTERM};
 use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
     sync::{atomic::AtomicUsize, Arc},
     thread::sleep,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use systemstat::Platform;
+use transport::{DeviceSelector, RusbTransport};
+
+//sleeping this much longer than asked means the system was suspended
+const RESUME_GAP: Duration = Duration::from_secs(5);
+//uploads smaller than this, like single frames, are quick enough not to need a progress bar
+const PROGRESS_MIN: usize = 1024 * 1024;
 
 #[derive(Parser)]
 #[command(about = "Change display of Kraken devices. Use at your own risk!")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, help = "Displays liquid screen")]
     liquid: bool,
     #[arg(short, long, help = "Displays blank screen")]
@@ -30,6 +61,57 @@ struct Cli {
     #[arg(long, short = 'k', help = "Set brightness (0-100)")]
     brightness: Option<u8>,
 
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Fade brightness changes over MS milliseconds, for -k and the brightness schedule"
+    )]
+    fade: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Rotate the lcd clockwise (0, 90, 180 or 270). Can be combined with display options",
+        value_parser = settings::parse_orientation
+    )]
+    orientation: Option<u16>,
+
+    #[arg(long, help = "Set fixed pump duty (20-100)")]
+    pump: Option<u8>,
+
+    #[arg(long, help = "Set fixed fan duty (0-100)")]
+    fan: Option<u8>,
+
+    #[arg(long, help = "Upload pump_curve and fan_curve from config")]
+    curves: bool,
+
+    #[arg(
+        long,
+        help = "Set lighting mode: off, fixed, breathing, fading, spectrum or per-led.\nWithout a mode, applies ring and logo lighting from config",
+        num_args = 0..=1,
+        default_missing_value = "config"
+    )]
+    lighting: Option<String>,
+
+    #[arg(
+        long,
+        help = "Lighting channel: ring, logo, external or sync",
+        default_value = "sync"
+    )]
+    channel: LightChannel,
+
+    #[arg(
+        long,
+        help = "Lighting colours, comma separated e.g. '#ff0000,#0000ff'"
+    )]
+    colors: Option<String>,
+
+    #[arg(
+        long,
+        help = "Lighting speed: slowest, slower, normal, faster or fastest",
+        default_value = "normal"
+    )]
+    speed: Speed,
+
     #[arg(
         long,
         help = "Run script, and get output as values. Expects same output as --values",
@@ -59,6 +141,63 @@ struct Cli {
     #[arg(long, help = "Read device status")]
     status: bool,
 
+    #[arg(
+        long,
+        help = "Show device details for bug reports, use --format json for json"
+    )]
+    info: bool,
+
+    #[arg(
+        long,
+        help = "Output format for --status (text, json or csv) and --info (text or json)",
+        default_value = "text"
+    )]
+    format: Format,
+
+    #[arg(
+        long,
+        help = "With --status, keep reading status every X seconds (e.g. 0.5)",
+        value_parser = parse_interval,
+        requires = "status"
+    )]
+    watch: Option<Duration>,
+
+    #[arg(long, help = "List connected devices")]
+    list_devices: bool,
+
+    #[arg(
+        long,
+        help = "Reattach kernel drivers left detached by a run that crashed or was killed"
+    )]
+    recover: bool,
+
+    #[arg(
+        long,
+        help = "Device to use, by serial or bus:addr (see --list-devices). Defaults to the first found"
+    )]
+    device: Option<DeviceSelector>,
+
+    #[arg(
+        long,
+        help = "Record every packet sent to and read from the device to a file, see replay"
+    )]
+    record: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Render values, images and gifs to this png or gif file instead of the device. Only for commands that draw"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(long, help = "Run on a simulated device instead of a connected one")]
+    no_device: bool,
+
+    #[arg(
+        long,
+        help = "With --output, make the corners outside the round lcd transparent"
+    )]
+    mask: bool,
+
     #[arg(short, long, help = "Displays CPU temperature")]
     cpu: bool,
 
@@ -77,15 +216,112 @@ struct Cli {
     repeat: Option<u64>,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Set up the system for krakenctl")]
+    Setup {
+        #[command(subcommand)]
+        setup: Setup,
+    },
+    #[command(about = "Send the packets of a recording made with --record to the device again")]
+    Replay {
+        #[arg(help = "Recording file")]
+        file: PathBuf,
+    },
+    #[command(
+        about = "Print the commands in a usbmon or USBPcap capture (pcap or pcapng) or a recording, and save uploaded images"
+    )]
+    Decode {
+        #[arg(help = "Capture or recording file")]
+        file: PathBuf,
+        #[arg(
+            long,
+            help = "Directory to save uploaded images to [default: current directory]"
+        )]
+        output: Option<PathBuf>,
+    },
+    #[command(about = "Manage images stored in the device's buckets")]
+    Bucket {
+        #[command(subcommand)]
+        action: BucketAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum BucketAction {
+    #[command(about = "List buckets and what they hold")]
+    List,
+    #[command(about = "Upload an image or gif into a bucket without showing it")]
+    Upload {
+        #[arg(help = "Bucket number, 0 and 1 are kept for frames")]
+        index: u8,
+        #[arg(help = "Image or gif file")]
+        file: PathBuf,
+    },
+    #[command(about = "Show the image in a bucket")]
+    Show {
+        #[arg(help = "Bucket number")]
+        index: u8,
+    },
+    #[command(about = "Delete a bucket")]
+    Delete {
+        #[arg(help = "Bucket number, 0 and 1 are kept for frames")]
+        index: u8,
+    },
+    #[command(about = "Clear all buckets")]
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum Setup {
+    #[command(about = "Write udev rules so users can access every supported device without root")]
+    Permissions {
+        #[arg(
+            long,
+            help = "Directory to write 99-kraken.rules to [default: /etc/udev/rules.d]"
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+/// seconds as a duration, which must be more than zero
+fn parse_interval(string: &str) -> Result<Duration, String> {
+    match string.parse::<f64>() {
+        Ok(secs) if secs > 0.0 => {
+            Duration::try_from_secs_f64(secs).map_err(|_| format!("'{string}' is too long"))
+        }
+        _ => Err(format!("'{string}' should be a number of seconds above 0")),
+    }
+}
+
 fn main() {
     let start = Instant::now();
 
     let clapp = Cli::parse();
 
-    let debug_level = clapp.debug.into();
+    let debug_level: DebugLevel = clapp.debug.clone().into();
 
+    //kernel drivers are put back even if we panic or are killed
+    guard::install();
+
+    //manager is dropped inside run, so kernel drivers are reattached before exiting
+    let result = run(clapp, debug_level);
+
+    if debug_level >= DebugLevel::Info {
+        println!("Ran in {}ms", start.elapsed().as_millis());
+    }
+    if let Err(e) = result {
+        eprintln!("{e}");
+        guard::exit(e.exit_code());
+    }
+}
+
+fn run(clapp: Cli, debug_level: DebugLevel) -> Result<(), KrakenError> {
     let settings = match Settings::load() {
-        Ok(s) => s,
+        Ok(s) => {
+            s.warn_problems();
+            s
+        }
         Err(e) => {
             if debug_level >= DebugLevel::Info {
                 println!("Could not load settings, using default. {e}");
@@ -99,39 +335,165 @@ fn main() {
         println!("{settings:?}");
     }
 
-    let mut manager = match Manager::new(debug_level, settings) {
-        Ok(m) => m,
-        Err(e) => {
-            println!("Could not create manager, {e}");
-            std::process::exit(1);
+    let bucket = match clapp.command {
+        Some(Command::Setup {
+            setup: Setup::Permissions { output },
+        }) => return setup::permissions(output.as_deref()),
+        Some(Command::Replay { file }) => return replay(&file, clapp.device.as_ref(), debug_level),
+        Some(Command::Decode { file, output }) => {
+            let events = decode::load(&file)?;
+            let name = file.file_stem().unwrap_or_default().to_string_lossy();
+            let saved = decode::decode(&events, &output.unwrap_or_default(), &name)?;
+            println!("{} packets, {} images saved", events.len(), saved.len());
+            return Ok(());
         }
+        Some(Command::Bucket { action }) => Some(action),
+        None => None,
     };
+    if clapp.list_devices {
+        return list_devices(debug_level);
+    }
+    if clapp.recover {
+        return guard::recover(debug_level);
+    }
+
+    //config orientation is applied whenever something is drawn
+    let draws = clapp.cpu
+        || clapp.values.is_some()
+        || clapp.script.is_some()
+        || clapp.image.is_some()
+        || clapp.gif.is_some()
+        || matches!(bucket, Some(BucketAction::Upload { .. }));
+    let orientation = clapp.orientation.or(settings.orientation.filter(|_| draws));
+
+    //--output renders on a simulated device, whose readings and settings are not real
+    let renders = clapp.cpu
+        || clapp.values.is_some()
+        || clapp.script.is_some()
+        || clapp.image.is_some()
+        || clapp.gif.is_some()
+        || clapp.liquid
+        || clapp.blank;
+    let controls = clapp.status
+        || clapp.info
+        || clapp.brightness.is_some()
+        || clapp.lighting.is_some()
+        || clapp.curves
+        || clapp.pump.is_some()
+        || clapp.fan.is_some()
+        || bucket.is_some();
+    if clapp.output.is_some() && (!renders || controls) {
+        return Err(KrakenError::Invalid(
+            "--output only renders --values, --cpu, --script, --image, --gif, --liquid or --blank, use --no-device to try other commands on a simulated device".to_string(),
+        ));
+    }
+
+    let manager = match &clapp.record {
+        _ if clapp.no_device || clapp.output.is_some() => Ok(Manager::preview(
+            debug_level,
+            settings,
+            clapp.output.clone(),
+            clapp.mask,
+        )),
+        Some(path) => Manager::recording(debug_level, settings, clapp.device.as_ref(), path),
+        None => Manager::new(debug_level, settings, clapp.device.as_ref()),
+    };
+    let mut manager = manager.inspect_err(|e| {
+        if let Some(diagnosis) = setup::diagnose(e) {
+            eprintln!("{diagnosis}");
+        }
+    })?;
+
+    //large uploads such as gifs take a while, so show how far along they are
+    if std::io::stderr().is_terminal() {
+        manager.on_progress(|sent, total| {
+            if total >= PROGRESS_MIN {
+                eprint!("{}", report::progress_bar(sent, total));
+                if sent == total {
+                    eprintln!();
+                }
+            }
+        });
+    }
+
+    if let Some(orientation) = orientation {
+        manager.set_orientation(orientation)?;
+    }
+    if let Some(fade) = clapp.fade {
+        manager.set_fade(Duration::from_millis(fade));
+    }
+
+    if let Some(action) = bucket {
+        return match action {
+            BucketAction::List => {
+                println!("{}", manager.bucket_list());
+                Ok(())
+            }
+            BucketAction::Upload { index, file } => manager.upload_to_bucket(index, &file),
+            BucketAction::Show { index } => manager.show_bucket(index),
+            BucketAction::Delete { index } => manager.delete_bucket(index),
+            BucketAction::Clear => manager.clear_buckets(),
+        };
+    }
 
     if clapp.liquid {
-        manager.set_liquid();
+        manager.set_liquid()
     } else if clapp.blank {
-        manager.set_blank();
+        manager.set_blank()
+    } else if clapp.info {
+        println!("{}", manager.details(clapp.format)?);
+        Ok(())
     } else if clapp.status {
-        manager.print_status();
+        let watch = clapp.watch;
+        if clapp.format == Format::Csv {
+            println!("{}", report::CSV_HEADER);
+        }
+        maybe_repeat(
+            &mut manager,
+            |manager| manager.print_status(clapp.format, watch.is_some()),
+            watch,
+            false,
+        )
     } else if let Some(br) = clapp.brightness {
-        if br <= 100 {
-            manager.set_brightness(br);
+        manager.set_brightness(br)
+    } else if let Some(mode) = clapp.lighting {
+        if mode == "config" {
+            manager.apply_lighting()
         } else {
-            println!("Brightness needs to be between 0 and 100");
+            let lighting = Lighting::new(
+                clapp.channel,
+                mode.parse().map_err(KrakenError::Invalid)?,
+                Lighting::parse_colors(clapp.colors.as_deref().unwrap_or_default())?,
+                clapp.speed,
+            )?;
+            manager.set_lighting(&lighting)
+        }
+    } else if clapp.curves {
+        manager.apply_curves()
+    } else if clapp.pump.is_some() || clapp.fan.is_some() {
+        if let Some(duty) = clapp.pump {
+            manager.set_pump_duty(duty)?;
         }
+        if let Some(duty) = clapp.fan {
+            manager.set_fan_duty(duty)?;
+        }
+        Ok(())
     } else if clapp.cpu {
         maybe_repeat(
-            move || {
+            &mut manager,
+            |manager| {
                 let ss = systemstat::System::new();
                 if let Ok(temp) = ss.cpu_temp() {
                     println!("temp is {temp}");
-                    manager.set_values_from_input(&format!("{temp}°"), time);
+                    manager.set_values_from_input(&format!("{temp}°"), time)
                 } else {
                     println!("Getting CPU temp is not supported on this platform");
+                    Ok(())
                 }
             },
-            clapp.repeat.clone(),
-        );
+            clapp.repeat.map(Duration::from_secs),
+            true,
+        )
     } else if let Some(input) = clapp.values {
         manager.set_values_from_input(&input, time)
     } else if let Some(path) = clapp.script {
@@ -139,10 +501,11 @@ fn main() {
             println!("running script '{path}'");
         }
         maybe_repeat(
-            move || {
+            &mut manager,
+            |manager| {
                 let output = std::process::Command::new(&path)
                     .output()
-                    .unwrap_or_else(|err| panic!("Could not run script '{path}': {err}"));
+                    .map_err(|e| KrakenError::file(&path, e))?;
                 let (stdo, stde, status) = (
                     String::from_utf8(output.stdout)
                         .unwrap_or_default()
@@ -155,23 +518,68 @@ fn main() {
                     println!("out:'{stdo}' err:'{stde}' status:{status:?}")
                 }
 
-                manager.set_values_from_input(&stdo, time);
+                manager.set_values_from_input(&stdo, time)?;
                 manager.reload_settings();
+                Ok(())
             },
-            clapp.repeat.clone(),
-        );
+            clapp.repeat.map(Duration::from_secs),
+            true,
+        )
     } else if let Some(path) = clapp.image {
         manager.set_image(&path)
     } else if let Some(path) = clapp.gif {
         manager.set_gif(&path)
+    } else {
+        Ok(())
     }
+}
 
+fn replay(
+    path: &Path,
+    device: Option<&DeviceSelector>,
+    debug_level: DebugLevel,
+) -> Result<(), KrakenError> {
+    let events = record::load(path)?;
     if debug_level >= DebugLevel::Info {
-        println!("Ran in {}ms", start.elapsed().as_millis());
+        println!("replaying {} packets from {}", events.len(), path.display());
+    }
+    let (mut transport, _) = RusbTransport::open(device, debug_level)?;
+    record::replay(&mut transport, &events, debug_level)
+}
+
+fn list_devices(debug_level: DebugLevel) -> Result<(), KrakenError> {
+    let devices = transport::list_devices()?;
+    if devices.is_empty() {
+        println!("No devices found");
+    }
+    for info in devices {
+        //listing must not disturb devices, so firmware is only read if nothing else holds them
+        let firmware = transport::peek_firmware(&info, debug_level)
+            .map(|(a, b, c)| format!("{a}.{b}.{c}"))
+            .unwrap_or_else(|| "unknown".to_string());
+        println!(
+            "{}:{} port {} serial {} firmware {} ({})",
+            info.bus,
+            info.address,
+            info.port_string(),
+            info.serial.as_deref().unwrap_or("unknown"),
+            firmware,
+            info.profile.name
+        );
     }
+    Ok(())
 }
 
-fn maybe_repeat<F: FnMut()>(mut func: F, rep: Option<u64>) {
+//run once, or keep running every rep until a signal is received
+//errors while repeating are logged and the loop carries on
+//a device that was reconnected or reset by suspend has its display restored
+//show_liquid puts the liquid screen back on exit, for modes that change the display
+fn maybe_repeat<F: FnMut(&mut Manager) -> Result<(), KrakenError>>(
+    manager: &mut Manager,
+    mut func: F,
+    rep: Option<Duration>,
+    show_liquid: bool,
+) -> Result<(), KrakenError> {
     let term: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
     signal_hook::flag::register_usize(SIGTERM, Arc::clone(&term), SIGTERM as usize).unwrap();
     signal_hook::flag::register_usize(SIGINT, Arc::clone(&term), SIGINT as usize).unwrap();
@@ -180,13 +588,48 @@ fn maybe_repeat<F: FnMut()>(mut func: F, rep: Option<u64>) {
         signal_hook::flag::register_usize(SIGTSTP, Arc::clone(&term), SIGTSTP as usize).unwrap();
         signal_hook::flag::register_usize(SIGHUP, Arc::clone(&term), SIGHUP as usize).unwrap();
     }
+    guard::set_graceful(rep.is_some());
+    let result = repeat_until_signal(manager, &mut func, rep, show_liquid, &term);
+    guard::set_graceful(false);
+    result
+}
+
+fn repeat_until_signal<F: FnMut(&mut Manager) -> Result<(), KrakenError>>(
+    manager: &mut Manager,
+    func: &mut F,
+    rep: Option<Duration>,
+    show_liquid: bool,
+    term: &AtomicUsize,
+) -> Result<(), KrakenError> {
     loop {
-        func(); //run the function
+        if rep.is_some() {
+            if let Err(e) = manager.apply_brightness_schedule() {
+                eprintln!("Could not set scheduled brightness {e}");
+            }
+        }
+        let result = func(manager); //run the function
 
         if let Some(repeat) = rep {
-            sleep(Duration::from_secs(repeat));
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+            //the device may have re-enumerated while running, so put the display back
+            if let Err(e) = manager.recover() {
+                eprintln!("Could not restore device {e}");
+            }
+            //monotonic time stops during suspend but wall clock time does not,
+            //so a long wall clock gap means the system slept and the device was reset
+            let before = SystemTime::now();
+            sleep(repeat);
+            let slept = before.elapsed().unwrap_or_default();
+            if slept > repeat + RESUME_GAP {
+                eprintln!("Resumed after {}s, reopening device", slept.as_secs());
+                if let Err(e) = manager.reopen() {
+                    eprintln!("Could not reopen device {e}");
+                }
+            }
         } else {
-            break;
+            return result;
         }
 
         let sig = term.load(std::sync::atomic::Ordering::Relaxed);
@@ -194,10 +637,10 @@ fn maybe_repeat<F: FnMut()>(mut func: F, rep: Option<u64>) {
             0 => (),
             signal => {
                 eprintln!("Got signal to exit with code {signal}");
-                if let Ok(mut man) = Manager::new(DebugLevel::None, Settings::default()) {
-                    man.set_liquid();
+                if show_liquid {
+                    return manager.set_liquid();
                 }
-                break;
+                return Ok(());
             }
         }
     }
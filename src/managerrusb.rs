@@ -1,14 +1,28 @@
-use crate::{imagetools, input::Input, settings::Settings};
+use crate::buckets::BucketMap;
+use crate::cooling::{Channel, Curve, PROFILE_POINTS};
+use crate::devices::{self, DeviceProfile, Feature};
+use crate::lighting::Lighting;
+#[cfg(test)]
+use crate::mock::MockTransport;
+use crate::record::Recorder;
+use crate::report::{self, Format};
+use crate::simulator::Simulator;
+use crate::transport::{
+    DeviceSelector, RusbTransport, Transport, BULK_OUT, INTERRUPT_IN, INTERRUPT_OUT,
+};
+use crate::{error::KrakenError, imagetools, input::Input, settings::Settings};
 use chrono::{DateTime, Local, Utc};
 use image::EncodableLayout;
-use rusb::{Context, DeviceHandle, LogLevel, UsbContext};
+use rusb::LogLevel;
 
 use std::{
-    error::Error,
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
     fs::File,
+    hash::{Hash, Hasher},
     io::{BufReader, Read},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
-    u8,
 };
 
 pub struct Endpoint {
@@ -18,6 +32,16 @@ pub struct Endpoint {
     address: u8,
 }
 
+//reads to skip over stale or unrelated reports before giving up
+const MAX_READS: usize = 8;
+//bulk payloads are written this many bytes at a time
+const BULK_CHUNK: usize = 64 * 1024;
+const MAX_BULK_RETRIES: usize = 3;
+//an unchanged frame is uploaded again after this long, unless set otherwise
+const FRAME_REFRESH: Duration = Duration::from_secs(60);
+//brightness fades change this often
+const FADE_STEP: Duration = Duration::from_millis(50);
+
 #[derive(Default)]
 pub struct Status {
     pub liquid_temp: f32,
@@ -25,16 +49,61 @@ pub struct Status {
     pub fan_speed: usize,
     pub pump_rate: u8,
     pub fan_rate: u8,
-    pub firmware: (u8, u8, u8),
+    /// None if it could not be read
+    pub firmware: Option<(u8, u8, u8)>,
+    /// from the usb descriptor, not the status reply
+    pub serial: Option<String>,
+    pub hardware_revision: Option<String>,
+    /// from the lcd info reply
+    pub lcd_brightness: Option<u8>,
+    /// degrees clockwise
+    pub lcd_orientation: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LcdMode {
+    Blank,
+    Liquid,
+    Bucket(u8),
+}
+
+impl Display for LcdMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LcdMode::Blank => write!(f, "blank"),
+            LcdMode::Liquid => write!(f, "liquid"),
+            LcdMode::Bucket(i) => write!(f, "bucket {i}"),
+        }
+    }
 }
 
 pub struct Manager {
     settings: Settings,
-    image_index: Option<usize>,
+    /// where each bucket lives in device memory, kept between runs for real devices
+    buckets: BucketMap,
+    lcd_mode: Option<LcdMode>,
+    /// last frame uploaded and whether it is a gif, to restore after a reconnect
+    last_frame: Option<(Vec<u8>, bool)>,
+    brightness: Option<u8>,
+    /// transport reconnects already restored
+    reconnects: usize,
+    /// simulated device, file and mask to render every new frame to instead of a device
+    preview: Option<(Simulator, PathBuf, bool)>,
+    progress: Option<Box<dyn FnMut(usize, usize)>>,
+    /// hash of the rendered frame on screen, when it was uploaded and the mode it was shown in
+    shown_frame: Option<(u64, Instant, Option<LcdMode>)>,
+    skipped_uploads: usize,
+    /// unchanged frames are uploaded again after this long
+    frame_refresh: Duration,
+    fade: Duration,
     pub debug_level: DebugLevel,
-    kernel_drivers: Vec<u8>,
+    pub profile: &'static DeviceProfile,
+    transport: Box<dyn Transport>,
 }
 
+/// buckets frames alternate between, so the one on screen is never being written
+const FRAME_BUCKETS: [u8; 2] = [0, 1];
+
 macro_rules! buff {
     ($s:expr) => { //empty, just 0s
         vec![u8;$s]
@@ -85,75 +154,115 @@ impl From<String> for DebugLevel {
     }
 }
 
-impl Drop for Manager {
-    fn drop(&mut self) {
-        if let Some(device_handle) = self.get_handle() {
-            if let Err(_e) = device_handle.reset() {
-                self.debug("Could not reset device", DebugLevel::Error);
-            }
+#[allow(clippy::vec_init_then_push)]
+impl Manager {
+    pub fn new(
+        debug_level: DebugLevel,
+        settings: Settings,
+        device: Option<&DeviceSelector>,
+    ) -> Result<Self, KrakenError> {
+        let (transport, profile) = RusbTransport::open(device, debug_level)?;
+        Ok(Manager::with_transport(
+            debug_level,
+            settings,
+            profile,
+            Box::new(transport),
+        ))
+    }
 
-            if cfg!(target_os = "linux") {
-                if self.kernel_drivers.is_empty() {
-                    self.debug("No kernel drivers to detach", DebugLevel::Info);
-                }
-                for &i in self.kernel_drivers.iter() {
-                    if device_handle.attach_kernel_driver(i).is_ok() {
-                        self.debug(format!("kernel attach:{i:?}"), DebugLevel::Debug);
-                    } else {
-                        self.debug(format!("could not attach kernel driver"), DebugLevel::Debug);
-                    }
-                }
-                if device_handle.reset().is_err() {
-                    self.debug("Could not reset device handle", DebugLevel::Error);
-                }
+    /// Like new, but every packet to and from the device is also written to a recording
+    pub fn recording(
+        debug_level: DebugLevel,
+        settings: Settings,
+        device: Option<&DeviceSelector>,
+        path: &Path,
+    ) -> Result<Self, KrakenError> {
+        let (transport, profile) = RusbTransport::open(device, debug_level)?;
+        let recorder = Recorder::create(path, Box::new(transport))?;
+        Ok(Manager::with_transport(
+            debug_level,
+            settings,
+            profile,
+            Box::new(recorder),
+        ))
+    }
+
+    /// Create a manager on top of any transport, e.g. the simulator, or the mock in tests
+    pub fn with_transport(
+        debug_level: DebugLevel,
+        settings: Settings,
+        profile: &'static DeviceProfile,
+        transport: Box<dyn Transport>,
+    ) -> Self {
+        //real devices keep a manifest of their buckets between runs
+        let manifest = transport
+            .info()
+            .and_then(|i| BucketMap::manifest_path(profile, i.serial.as_deref()));
+        let mut buckets = BucketMap::new(profile, manifest);
+        if let Err(e) = buckets.load() {
+            if debug_level >= DebugLevel::Warning {
+                println!("Ignoring bucket manifest. {e}");
             }
-        } else {
-            self.debug(
-                "Could not open device handle when closing manager",
-                DebugLevel::Error,
-            );
+        }
+        Manager {
+            buckets,
+            lcd_mode: None,
+            last_frame: None,
+            brightness: None,
+            reconnects: 0,
+            preview: None,
+            progress: None,
+            shown_frame: None,
+            skipped_uploads: 0,
+            frame_refresh: FRAME_REFRESH,
+            fade: Duration::ZERO,
+            debug_level,
+            settings,
+            profile,
+            transport,
         }
     }
-}
 
-#[allow(clippy::vec_init_then_push)]
-impl Manager {
-    pub fn new(debug_level: DebugLevel, settings: Settings) -> Result<Self, Box<dyn Error>> {
-        let mut vec = vec![];
-        let context = rusb::Context::new()?;
-
-        let device_handle = context
-            .open_device_with_vid_pid(0x1e71, 0x3008)
-            .ok_or("Could not open kraken")?;
-
-        device_handle.reset()?;
+    /// Create a manager on a simulated device, with the simulator's profile
+    pub fn simulated(debug_level: DebugLevel, settings: Settings, simulator: Simulator) -> Self {
+        let profile = simulator.profile();
+        Manager::with_transport(debug_level, settings, profile, Box::new(simulator))
+    }
 
-        if cfg!(target_os = "linux") {
-            if debug_level >= DebugLevel::Info {
-                println!("config: {:?}", device_handle.active_configuration());
-            }
+    /// Create a manager that renders to a file instead of a device, or nowhere without output.
+    /// Runs on a simulated device with the default profile
+    pub fn preview(
+        debug_level: DebugLevel,
+        settings: Settings,
+        output: Option<PathBuf>,
+        mask: bool,
+    ) -> Self {
+        let simulator = Simulator::new(devices::default_profile());
+        let mut manager = Manager::simulated(debug_level, settings, simulator.clone());
+        manager.preview = output.map(|path| (simulator, path, mask));
+        manager
+    }
 
-            for i in [0, 1] {
-                let has_k = device_handle.kernel_driver_active(i).unwrap_or_default();
-                if has_k {
-                    device_handle.detach_kernel_driver(i)?;
-                    vec.push(i);
-                    if debug_level >= DebugLevel::Debug {
-                        println!("kernel detach:{i:?}");
-                    }
-                } else {
-                    if debug_level >= DebugLevel::Debug {
-                        println!("kernel driver not active:{i:?}");
-                    }
-                }
-            }
-        }
-        Ok(Manager {
-            image_index: None,
+    /// Create a manager on the mock transport, with the default profile
+    #[cfg(test)]
+    pub fn mock(debug_level: DebugLevel, settings: Settings, mock: MockTransport) -> Self {
+        Manager::with_transport(
             debug_level,
             settings,
-            kernel_drivers: vec,
-        })
+            devices::default_profile(),
+            Box::new(mock),
+        )
+    }
+
+    fn supports(&self, feature: Feature) -> Result<(), KrakenError> {
+        if self.profile.supports(feature) {
+            Ok(())
+        } else {
+            Err(KrakenError::Unsupported(format!(
+                "{} does not support {feature:?}",
+                self.profile.name
+            )))
+        }
     }
 
     //write details to stdout if debugging enabled
@@ -167,244 +276,773 @@ impl Manager {
         }
     }
 
-    pub fn details(&self) -> String {
-        String::new()
+    /// report on the device for bug reports, as text or json
+    pub fn details(&mut self, format: Format) -> Result<String, KrakenError> {
+        //a bug report is most useful when something is wrong, so report what can be read
+        let firmware = match self.firmware() {
+            Ok((a, b, c)) => Some(format!("{a}.{b}.{c}")),
+            Err(e) => {
+                self.debug(format!("Could not read firmware {e}"), DebugLevel::Warning);
+                None
+            }
+        };
+        let (brightness, orientation) = match self.lcd_info() {
+            Ok((brightness, orientation)) => (Some(brightness), Some(orientation)),
+            Err(e) => {
+                self.debug(format!("Could not read lcd info {e}"), DebugLevel::Warning);
+                (None, None)
+            }
+        };
+        let info = self.transport.info();
+        let path = info.map(|i| i.port_string());
+        let bus_address = info.map(|i| format!("{}:{}", i.bus, i.address));
+        let serial = info.and_then(|i| i.serial.clone());
+        let hardware = info.map(|i| i.hardware_revision.clone());
+        let drivers = self.transport.detached_drivers();
+        //buckets are only known once cleared, or from the manifest
+        let occupied = self.buckets.known().then(|| {
+            (0..self.profile.bucket_count)
+                .filter_map(|i| self.buckets.get(i).map(|a| (i, a.size)))
+                .collect::<Vec<(u8, usize)>>()
+        });
+        let profile = self.profile;
+
+        match format {
+            Format::Json => {
+                let drivers = drivers
+                    .iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",");
+                let buckets = match &occupied {
+                    Some(occupied) => format!(
+                        "[{}]",
+                        occupied
+                            .iter()
+                            .map(|(i, size)| format!("{{\"index\":{i},\"bytes\":{size}}}"))
+                            .collect::<Vec<String>>()
+                            .join(",")
+                    ),
+                    None => "null".to_string(),
+                };
+                let string =
+                    |s: &Option<String>| report::json_or_null(s, |s| report::json_string(s));
+                Ok(format!(
+                    "{{\"device\":{},\"vid\":\"{:04x}\",\"pid\":\"{:04x}\",\"usb_path\":{},\"bus_address\":{},\"serial\":{},\"hardware_revision\":{},\"firmware\":{},\"lcd_width\":{},\"lcd_height\":{},\"lcd_brightness\":{},\"lcd_orientation\":{},\"kernel_drivers_detached\":[{drivers}],\"bucket_count\":{},\"buckets\":{buckets}}}",
+                    report::json_string(profile.name),
+                    profile.vid,
+                    profile.pid,
+                    string(&path),
+                    string(&bus_address),
+                    string(&serial),
+                    string(&hardware),
+                    string(&firmware),
+                    profile.width,
+                    profile.height,
+                    report::json_or_null(&brightness, |b| b.to_string()),
+                    report::json_or_null(&orientation, |o| o.to_string()),
+                    profile.bucket_count,
+                ))
+            }
+            Format::Text => {
+                let drivers = if drivers.is_empty() {
+                    "none".to_string()
+                } else {
+                    drivers
+                        .iter()
+                        .map(|d| format!("interface {d}"))
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                };
+                let buckets = match &occupied {
+                    Some(occupied) => format!(
+                        "{}/{} used {}",
+                        occupied.len(),
+                        profile.bucket_count,
+                        occupied
+                            .iter()
+                            .map(|(i, size)| format!("{i}:{size} bytes"))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    ),
+                    None => "unknown until an image is uploaded".to_string(),
+                };
+                Ok(format!(
+                    "Device {} ({:04x}:{:04x})
+USB Path {} ({})
+Serial {}
+Hardware {}
+Firmware {}
+LCD Resolution {}x{}
+LCD Brightness {}
+LCD Orientation {}
+Kernel Drivers Detached {drivers}
+Buckets {buckets}",
+                    profile.name,
+                    profile.vid,
+                    profile.pid,
+                    unknown(&path),
+                    unknown(&bus_address),
+                    unknown(&serial),
+                    unknown(&hardware),
+                    unknown(&firmware),
+                    profile.width,
+                    profile.height,
+                    unknown(&brightness.map(|b| format!("{b}%"))),
+                    unknown(&orientation.map(|o| format!("{o}°"))),
+                ))
+            }
+            Format::Csv => Err(KrakenError::Invalid(
+                "Device info is only available as text or json".to_string(),
+            )),
+        }
+    }
+
+    pub fn set_blank(&mut self) -> Result<(), KrakenError> {
+        self.supports(Feature::Blank)?;
+        self.write_to_interrupt(buff![0x38, 1 ;64])?;
+        self.lcd_mode = Some(LcdMode::Blank);
+        self.buckets.set_shown(None);
+        Ok(())
+    }
+
+    pub fn set_liquid(&mut self) -> Result<(), KrakenError> {
+        self.supports(Feature::Liquid)?;
+        self.write_to_interrupt(buff![0x38,1,2;64])?;
+        self.lcd_mode = Some(LcdMode::Liquid);
+        self.buckets.set_shown(None);
+        Ok(())
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), KrakenError> {
+        self.supports(Feature::Brightness)?;
+        if brightness > 100 {
+            return Err(KrakenError::Invalid(
+                "Brightness needs to be between 0 and 100".to_string(),
+            ));
+        }
+        //the same command sets orientation, so keep the current one
+        //if it cannot be read, leave the lcd alone rather than rotating it back to 0
+        let mut info = None;
+        let orientation = match self.settings.orientation {
+            Some(orientation) => orientation,
+            None => {
+                let (current, orientation) = self.lcd_info()?;
+                info = Some(current);
+                orientation
+            }
+        };
+        //with a fade, step from the current brightness instead of jumping
+        let from = match self.brightness.or(info) {
+            _ if self.fade.is_zero() => None,
+            Some(from) => Some(from),
+            None => self.lcd_info().ok().map(|(b, _)| b),
+        };
+        if let Some(from) = from.filter(|&from| from != brightness) {
+            let steps = (self.fade.as_millis() / FADE_STEP.as_millis()).max(1) as i32;
+            for step in 1..steps {
+                let level = from as i32 + (brightness as i32 - from as i32) * step / steps;
+                self.write_lcd_settings(level as u8, orientation)?;
+                std::thread::sleep(self.fade / steps as u32);
+            }
+        }
+        self.write_lcd_settings(brightness, orientation)?;
+        self.brightness = Some(brightness);
+        Ok(())
+    }
+
+    /// Upload unchanged frames again after this long
+    pub fn set_frame_refresh(&mut self, refresh: Duration) {
+        self.frame_refresh = refresh;
+    }
+
+    /// Fade later brightness changes over this long
+    pub fn set_fade(&mut self, fade: Duration) {
+        self.fade = fade;
+    }
+
+    /// Set the brightness the schedule in the config gives for now, if it changed
+    pub fn apply_brightness_schedule(&mut self) -> Result<(), KrakenError> {
+        let Some(schedule) = &self.settings.brightness_schedule else {
+            return Ok(());
+        };
+        let brightness = schedule.brightness_at(Local::now().time());
+        if self.brightness == Some(brightness) {
+            return Ok(());
+        }
+        self.debug(
+            format!("Scheduled brightness {brightness}%"),
+            DebugLevel::Info,
+        );
+        self.set_brightness(brightness)
+    }
+
+    /// rotate the lcd clockwise by 0, 90, 180 or 270 degrees.
+    /// Devices that cannot rotate have the frames rotated before upload instead
+    pub fn set_orientation(&mut self, orientation: u16) -> Result<(), KrakenError> {
+        if !matches!(orientation, 0 | 90 | 180 | 270) {
+            return Err(KrakenError::Invalid(
+                "Orientation needs to be 0, 90, 180 or 270".to_string(),
+            ));
+        }
+        self.settings.orientation = Some(orientation);
+        if !self.profile.supports(Feature::Orientation) {
+            self.debug(
+                format!(
+                    "{} cannot rotate, rotating frames instead",
+                    self.profile.name
+                ),
+                DebugLevel::Info,
+            );
+            return Ok(());
+        }
+        //the same command sets brightness, so keep the current one
+        //if it cannot be read, leave the lcd alone rather than guessing and lighting it up
+        let brightness = match self.brightness {
+            Some(brightness) => brightness,
+            None => self.lcd_info()?.0,
+        };
+        self.write_lcd_settings(brightness, orientation)
     }
 
-    pub fn set_blank(&mut self) {
-        if let Some(dev) = self.get_handle() {
-            self.write_to_interrupt(&dev, buff![0x38, 1 ;64]);
+    fn write_lcd_settings(&mut self, brightness: u8, orientation: u16) -> Result<(), KrakenError> {
+        self.write_to_interrupt(
+            buff![0x30, 2, 1, brightness, 0, 0, 1, (orientation / 90) as u8; 64],
+        )
+    }
+
+    //rotation still needed on frames after what the device does itself
+    fn render_rotation(&self) -> u16 {
+        if self.profile.supports(Feature::Orientation) {
+            0
+        } else {
+            self.settings.orientation.unwrap_or(0)
         }
     }
 
-    pub fn set_liquid(&mut self) {
-        if let Some(device_handle) = self.get_handle() {
-            self.write_to_interrupt(&device_handle, buff![0x38,1,2;64]);
+    pub fn set_pump_duty(&mut self, duty: u8) -> Result<(), KrakenError> {
+        self.set_duty(Channel::Pump, duty)
+    }
+
+    pub fn set_fan_duty(&mut self, duty: u8) -> Result<(), KrakenError> {
+        self.set_duty(Channel::Fan, duty)
+    }
+
+    //fixed duty is a profile with the same duty at every temperature
+    fn set_duty(&mut self, channel: Channel, duty: u8) -> Result<(), KrakenError> {
+        self.supports(Feature::Cooling)?;
+        let valid = channel.validate(duty)?;
+        if valid != duty {
+            self.debug(
+                format!("{} duty raised to minimum of {valid}%", channel.name()),
+                DebugLevel::Warning,
+            );
         }
+        self.set_speed_profile(channel, &[valid; PROFILE_POINTS])
     }
 
-    pub fn set_brightness(&mut self, brightness: u8) {
-        if brightness > 100 {
-            return;
+    fn set_speed_profile(
+        &mut self,
+        channel: Channel,
+        duties: &[u8; PROFILE_POINTS],
+    ) -> Result<(), KrakenError> {
+        let mut bytes = buff![0x72, channel.id(), 0, 0; 64];
+        bytes[4..4 + PROFILE_POINTS].copy_from_slice(duties);
+        self.debug(
+            format!("setting {} profile {duties:?}", channel.name()),
+            DebugLevel::Debug,
+        );
+        self.write_to_interrupt(bytes)
+    }
+
+    /// upload the pump and fan curves from settings as the device's internal profiles
+    pub fn apply_curves(&mut self) -> Result<(), KrakenError> {
+        self.supports(Feature::Cooling)?;
+        if self.settings.pump_curve.is_none() && self.settings.fan_curve.is_none() {
+            return Err(KrakenError::Invalid(
+                "No pump_curve or fan_curve in config".to_string(),
+            ));
         }
-        if let Some(device_handle) = self.get_handle() {
-            self.write_to_interrupt(&device_handle, buff![0x30,2,1,brightness;64]);
+        for channel in [Channel::Pump, Channel::Fan] {
+            if let Some(curve) = self.curve(channel) {
+                let profile = curve.profile(channel);
+                self.set_speed_profile(channel, &profile)?;
+            }
         }
+        Ok(())
     }
 
-    pub fn loop_images(&mut self) {
-        if let Some(device_handle) = self.get_handle() {
-            for i in 1..=16 {
-                self.set_image_at_index(&device_handle, i);
-                std::thread::sleep(Duration::from_millis(1500));
+    fn curve(&self, channel: Channel) -> Option<&Curve> {
+        match channel {
+            Channel::Pump => self.settings.pump_curve.as_ref(),
+            Channel::Fan => self.settings.fan_curve.as_ref(),
+        }
+    }
+
+    //describe the configured curve, and whether the reported duty matches it
+    fn curve_status(&self, channel: Channel, liquid_temp: f32, duty: u8) -> String {
+        match self.curve(channel) {
+            Some(curve) => {
+                let expected = curve.duty_at(liquid_temp).max(channel.min_duty());
+                let state = if expected.abs_diff(duty) <= 1 {
+                    "active"
+                } else {
+                    "not active"
+                };
+                format!("{curve} ({state}, expects {expected}%)")
             }
+            None => "none".to_string(),
         }
     }
 
-    fn set_image_at_index(&mut self, device_handle: &DeviceHandle<Context>, index: u8) {
-        self.write_to_interrupt(&device_handle, buff![0x38,1,4,index;64]);
+    pub fn set_lighting(&mut self, lighting: &Lighting) -> Result<(), KrakenError> {
+        self.supports(Feature::Lighting)?;
+        self.debug(format!("setting lighting {lighting:?}"), DebugLevel::Debug);
+        for packet in lighting.packets() {
+            self.write_to_interrupt(packet)?;
+        }
+        Ok(())
     }
 
-    pub fn set_image_with_bytes(&mut self, img_bytes: &[u8], is_gif: bool) {
-        if let Some(device_handle) = self.get_handle() {
-            if self.image_index.is_none() {
-                //clear images
-                for i in 0..16 {
-                    self.write_to_interrupt(&device_handle, buff![0x30,4,i;64]);
-                }
+    /// apply the ring and logo lighting from settings
+    pub fn apply_lighting(&mut self) -> Result<(), KrakenError> {
+        let effects = [
+            self.settings.ring_lighting.clone(),
+            self.settings.logo_lighting.clone(),
+        ];
+        if effects.iter().all(|x| x.is_none()) {
+            return Err(KrakenError::Invalid(
+                "No ring_mode or logo_mode in config".to_string(),
+            ));
+        }
+        for lighting in effects.iter().flatten() {
+            self.set_lighting(lighting)?;
+        }
+        Ok(())
+    }
 
-                let rand_index: usize = rand::random::<usize>() % 16;
-                self.image_index = Some(rand_index);
-            }
+    pub fn loop_images(&mut self) -> Result<(), KrakenError> {
+        for i in 1..=self.profile.bucket_count {
+            self.set_image_at_index(i)?;
+            std::thread::sleep(Duration::from_millis(1500));
+        }
+        Ok(())
+    }
+
+    fn set_image_at_index(&mut self, index: u8) -> Result<(), KrakenError> {
+        self.write_to_interrupt(buff![0x38,1,4,index;64])?;
+        self.lcd_mode = Some(LcdMode::Bucket(index));
+        self.buckets.set_shown(Some(index));
+        Ok(())
+    }
 
-            let mut mem_index = [[0u8; 2]; 16];
-            mem_index[0] = [0x00, 0x00];
-            mem_index[1] = [0x90, 0x01];
-            mem_index[2] = [0x20, 0x03];
-            mem_index[3] = [0xb0, 0x04];
-            mem_index[4] = [0x40, 0x06];
-            mem_index[5] = [0xd0, 0x07];
-            mem_index[6] = [0x60, 0x09];
-            mem_index[7] = [0xf0, 0x0a];
-            mem_index[8] = [0x80, 0x0c];
-            mem_index[9] = [0x10, 0x0e];
-            mem_index[10] = [0xa0, 0x0f];
-            mem_index[11] = [0x30, 0x11];
-            mem_index[12] = [0xc0, 0x12];
-            mem_index[13] = [0x50, 0x14];
-            mem_index[14] = [0xe0, 0x15];
-            mem_index[15] = [0x70, 0x17];
-
-            //delete bucket
-            for i in 0..2 {
-                //let random = rand::random::<usize>() % 16;
-                self.image_index = Some(i % 16);
-                let index = (self.image_index.unwrap() % 16) as u8;
-
-                self.image_index = Some(self.image_index.unwrap() + 1);
-
-                self.write_to_interrupt(&device_handle, buff![0x32,2,index;64]);
-
-                let setup_bytes = buff![
-            0x32,
-            1,
-            index,
-            index+1,
-            mem_index[index as usize][0],
-            mem_index[index as usize][1],
-            0x90,
-            1,
-            1
-            ;64];
-
-                self.write_to_interrupt(&device_handle, setup_bytes);
-                let delay = 100;
-                //start bulk write
-                self.write_to_interrupt(&device_handle, buff![0x36,1,index; 64]);
-                std::thread::sleep(Duration::from_millis(delay));
-                //BULK
-                let mut header = buff![
-        0x12,
-        0xfa,
-        0x01,
-        0xe8,
-        0xab,
-        0xcd,
-        0xef,
-        0x98,
-        0x76,
-        0x54,
+    /// Upload a frame or gif into a bucket, without showing it
+    fn upload_bucket(
+        &mut self,
+        index: u8,
+        img_bytes: &[u8],
+        is_gif: bool,
+    ) -> Result<(), KrakenError> {
+        let allocation = self.buckets.allocate(index, img_bytes.len(), is_gif)?;
+        //delete bucket
+        self.write_to_interrupt(buff![0x32,2,index;64])?;
+
+        let [start_lo, start_hi] = allocation.start.to_le_bytes();
+        let [size_lo, size_hi] = allocation.blocks.to_le_bytes();
+        let setup_bytes = buff![
         0x32,
-        0x10,
-         0x2,
-         0x0,
-         0x0,
-         0x0,
-         0x0,
-        0x40,
-         0x6
-         ;512];
-                if is_gif {
-                    header[12] = 1;
-                }
+        1,
+        index,
+        index+1,
+        start_lo,
+        start_hi,
+        size_lo,
+        size_hi,
+        1
+        ;64];
 
-                self.write_to_bulk(&device_handle, &header);
-                std::thread::sleep(Duration::from_millis(delay));
-                self.write_to_bulk(&device_handle, img_bytes);
+        self.write_and_wait(setup_bytes)?;
+        //start bulk write
+        self.write_and_wait(buff![0x36,1,index; 64])?;
+        //BULK
+        let mut header = buff![
+    0x12,
+    0xfa,
+    0x01,
+    0xe8,
+    0xab,
+    0xcd,
+    0xef,
+    0x98,
+    0x76,
+    0x54,
+    0x32,
+    0x10,
+     0x2,
+     0x0,
+     0x0,
+     0x0,
+     0x0,
+    0x40,
+     0x6
+     ;512];
+        if is_gif {
+            header[12] = 1;
+        }
+        //payload size
+        header[16..20].copy_from_slice(&(img_bytes.len() as u32).to_le_bytes());
 
-                //end bulk write
-                self.write_to_interrupt(&device_handle, buff![0x36,2;64]);
-                //wait for image to finish sending
+        self.write_to_bulk(&header)?;
+        self.write_to_bulk(img_bytes)?;
 
-                //show image at index
-                self.set_image_at_index(&device_handle, index);
+        //end bulk write, the device acknowledges once the bucket is complete.
+        //without that the bucket may be incomplete, so must not be shown
+        if let Err(e) = self.write_and_wait(buff![0x36,2;64]) {
+            self.save_buckets();
+            return Err(match e {
+                KrakenError::Timeout | KrakenError::Protocol(_) => KrakenError::Protocol(format!(
+                    "Device did not confirm the upload into bucket {index}. {e}"
+                )),
+                e => e,
+            });
+        }
+        self.buckets.insert(index, allocation);
+        self.save_buckets();
+        Ok(())
+    }
+
+    //the manifest only saves work in later runs, so problems writing it are not fatal
+    fn save_buckets(&mut self) {
+        if let Err(e) = self.buckets.save() {
+            self.debug(
+                format!("Could not save bucket manifest. {e}"),
+                DebugLevel::Warning,
+            );
+        }
+    }
+
+    pub fn set_image_with_bytes(
+        &mut self,
+        img_bytes: &[u8],
+        is_gif: bool,
+    ) -> Result<(), KrakenError> {
+        self.supports(if is_gif { Feature::Gif } else { Feature::Image })?;
+        self.shown_frame = None;
+        if !self.buckets.known() {
+            self.clear_buckets()?;
+        }
+
+        //upload into the frame bucket not on screen, and only switch to it once complete
+        //the manifest records what the last run left on screen
+        let index = match self.buckets.shown() {
+            Some(i) if i == FRAME_BUCKETS[0] => FRAME_BUCKETS[1],
+            _ => FRAME_BUCKETS[0],
+        };
+        self.upload_bucket(index, img_bytes, is_gif)?;
+        self.set_image_at_index(index)?;
+        self.last_frame = Some((img_bytes.to_vec(), is_gif));
+        if let Some((simulator, path, mask)) = &self.preview {
+            for problem in simulator.take_problems() {
+                self.debug(
+                    format!("Device would reject: {problem}"),
+                    DebugLevel::Warning,
+                );
             }
+            simulator.save(path, *mask)?;
+            self.debug(format!("Rendered to {}", path.display()), DebugLevel::Info);
         }
+        Ok(())
     }
 
-    pub fn set_image(&mut self, path: &str) {
-        let img4 = imagetools::convert_image_from_path(path);
-        let img5 = img4.to_rgba8();
+    /// If the device was reopened since the last call, e.g. after it re-enumerated,
+    /// its memory and lcd settings are back to defaults, so put back what this manager set.
+    /// Returns whether anything was restored
+    pub fn recover(&mut self) -> Result<bool, KrakenError> {
+        let reconnects = self.transport.stats().reconnects;
+        if reconnects == self.reconnects {
+            return Ok(false);
+        }
+        self.reconnects = reconnects;
+        self.debug(
+            "Device was reconnected, restoring display",
+            DebugLevel::Warning,
+        );
+
+        //buckets are empty again, so clear them before the next upload
+        self.buckets.forget();
+        self.save_buckets();
+        if let Some(orientation) = self.settings.orientation {
+            self.set_orientation(orientation)?;
+        }
+        if let Some(brightness) = self.brightness {
+            self.set_brightness(brightness)?;
+        }
+        match (self.lcd_mode, self.last_frame.clone()) {
+            (Some(LcdMode::Bucket(_)), Some((frame, is_gif))) => {
+                self.set_image_with_bytes(&frame, is_gif)?
+            }
+            (Some(LcdMode::Liquid), _) => self.set_liquid()?,
+            (Some(LcdMode::Blank), _) => self.set_blank()?,
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    /// Open the device again and restore the display, for when it may have been reset
+    /// without the session noticing, e.g. after the system resumes from suspend
+    pub fn reopen(&mut self) -> Result<(), KrakenError> {
+        self.transport.reopen()?;
+        self.recover()?;
+        Ok(())
+    }
+
+    pub fn set_image(&mut self, path: &str) -> Result<(), KrakenError> {
+        let img4 = imagetools::convert_image_from_path(path, self.profile.width)?;
+        let img5 = imagetools::orient(img4, self.render_rotation()).to_rgba8();
         let img_bytes = img5.as_bytes();
-        self.set_image_with_bytes(img_bytes, false);
+        self.set_image_with_bytes(img_bytes, false)
     }
 
-    pub fn set_gif(&mut self, path: &str) {
+    pub fn set_gif(&mut self, path: &str) -> Result<(), KrakenError> {
         //  let img4=imagetools::convert_image_from_path(path);
         //let img_bytes = img4.as_bytes();
 
-        let f = File::open(path).unwrap();
+        let img_bytes = self.gif_bytes(path)?;
+        self.set_image_with_bytes(&img_bytes, true)
+    }
+
+    fn gif_bytes(&self, path: &str) -> Result<Vec<u8>, KrakenError> {
+        let f = File::open(path).map_err(|e| KrakenError::file(path, e))?;
         let mut img_bytes = vec![];
         let mut reader = BufReader::new(f);
-        reader.read_to_end(&mut img_bytes).unwrap();
+        reader
+            .read_to_end(&mut img_bytes)
+            .map_err(|e| KrakenError::file(path, e))?;
+        imagetools::rotate_gif(&img_bytes, self.render_rotation())
+    }
+
+    fn check_bucket(&self, index: u8) -> Result<(), KrakenError> {
+        if index >= self.profile.bucket_count {
+            return Err(KrakenError::Invalid(format!(
+                "Bucket {index} does not exist, {} has buckets 0 to {}",
+                self.profile.name,
+                self.profile.bucket_count - 1
+            )));
+        }
+        Ok(())
+    }
+
+    //frames are uploaded into the frame buckets in turn, so anything else put there is lost
+    fn check_stored_bucket(&self, index: u8) -> Result<(), KrakenError> {
+        self.check_bucket(index)?;
+        if FRAME_BUCKETS.contains(&index) {
+            return Err(KrakenError::Invalid(format!(
+                "Buckets {} and {} hold the frames of values, images and gifs, use buckets {} to {}",
+                FRAME_BUCKETS[0],
+                FRAME_BUCKETS[1],
+                FRAME_BUCKETS[1] + 1,
+                self.profile.bucket_count - 1
+            )));
+        }
+        Ok(())
+    }
+
+    /// Upload an image or gif into a bucket without showing it, so it can be shown later
+    pub fn upload_to_bucket(&mut self, index: u8, path: &Path) -> Result<(), KrakenError> {
+        self.check_stored_bucket(index)?;
+        let path = path.to_string_lossy();
+        let is_gif = path.to_lowercase().ends_with(".gif");
+        self.supports(if is_gif { Feature::Gif } else { Feature::Image })?;
+        let img_bytes = if is_gif {
+            self.gif_bytes(&path)?
+        } else {
+            let img = imagetools::convert_image_from_path(&path, self.profile.width)?;
+            imagetools::orient(img, self.render_rotation())
+                .to_rgba8()
+                .into_raw()
+        };
+        //without a manifest nothing says where other buckets are, so start from empty memory
+        //rather than writing over them
+        if !self.buckets.known() {
+            self.debug(
+                "Buckets on the device are unknown, clearing them first",
+                DebugLevel::Warning,
+            );
+            self.clear_buckets()?;
+        }
+        self.upload_bucket(index, &img_bytes, is_gif)?;
+        self.debug(
+            format!("Uploaded {} bytes to bucket {index}", img_bytes.len()),
+            DebugLevel::Info,
+        );
+        Ok(())
+    }
+
+    /// Show what is already in a bucket
+    pub fn show_bucket(&mut self, index: u8) -> Result<(), KrakenError> {
+        self.check_bucket(index)?;
+        self.set_image_at_index(index)
+    }
+
+    pub fn delete_bucket(&mut self, index: u8) -> Result<(), KrakenError> {
+        self.check_stored_bucket(index)?;
+        self.write_to_interrupt(buff![0x32,2,index;64])?;
+        self.buckets.free(index);
+        self.save_buckets();
+        Ok(())
+    }
+
+    pub fn clear_buckets(&mut self) -> Result<(), KrakenError> {
+        for i in 0..self.profile.bucket_count {
+            self.write_to_interrupt(buff![0x30,4,i;64])?;
+        }
+        self.buckets.clear();
+        self.save_buckets();
+        Ok(())
+    }
+
+    /// Each bucket with where it sits in device memory and what it holds, if known
+    pub fn bucket_list(&self) -> String {
+        self.buckets.describe()
+    }
 
-        self.set_image_with_bytes(&img_bytes, true);
+    /// print one status record, text records get a time line if timestamp is set
+    pub fn print_status(&mut self, format: Format, timestamp: bool) -> Result<(), KrakenError> {
+        let status = self.query()?;
+        let now = Local::now();
+        match format {
+            Format::Json => println!("{}", report::json(&status, now)),
+            Format::Csv => println!("{}", report::csv(&status, now)),
+            Format::Text => {
+                if timestamp {
+                    println!("Time {}", now.format("%Y-%m-%d %H:%M:%S"));
+                }
+                self.print_status_text(&status);
+            }
+        }
+        Ok(())
     }
 
-    pub fn print_status(&mut self) {
-        let status = self.query();
+    fn print_status_text(&self, status: &Status) {
         println!(
             "Liquid {}°C
 Fan Speed {} rpm
 Fan Rate {}%
+Fan Curve {}
 Pump Speed {} rpm
 Pump Rate {}%
-Firmware {}.{}.{}",
+Pump Curve {}
+Firmware {}
+Serial {}
+Hardware {}
+LCD Brightness {}
+LCD Orientation {}",
             status.liquid_temp,
             status.fan_speed,
             status.fan_rate,
+            self.curve_status(Channel::Fan, status.liquid_temp, status.fan_rate),
             status.pump_speed,
             status.pump_rate,
-            status.firmware.0,
-            status.firmware.1,
-            status.firmware.2
+            self.curve_status(Channel::Pump, status.liquid_temp, status.pump_rate),
+            unknown(&status.firmware.map(|(a, b, c)| format!("{a}.{b}.{c}"))),
+            unknown(&status.serial),
+            unknown(&status.hardware_revision),
+            unknown(&status.lcd_brightness.map(|b| format!("{b}%"))),
+            unknown(&status.lcd_orientation.map(|o| format!("{o}°"))),
         );
     }
-    fn query(&mut self) -> Status {
-        let bytes = self.write_and_read_interface(&buff![0x74, 1; 64]);
-        let bytes_firm = self.write_and_read_interface(&buff![0x10, 1; 64]);
 
-        if bytes.len() != 64 || bytes_firm.len() != 64 {
-            return Status::default();
-        }
+    pub fn query(&mut self) -> Result<Status, KrakenError> {
+        self.supports(Feature::Status)?;
+        let bytes = self.write_and_read_interface(&buff![0x74, 1; 64])?;
+        //one failed read should not cost a whole sample, e.g. while watching
+        let firmware = match self.firmware() {
+            Ok(firmware) => Some(firmware),
+            Err(e) => {
+                self.debug(format!("Could not read firmware {e}"), DebugLevel::Warning);
+                None
+            }
+        };
+        let (lcd_brightness, lcd_orientation) = match self.lcd_info() {
+            Ok((brightness, orientation)) => (Some(brightness), Some(orientation)),
+            Err(e) => {
+                self.debug(format!("Could not read lcd info {e}"), DebugLevel::Warning);
+                (None, None)
+            }
+        };
+        let info = self.transport.info();
 
-        Status {
+        Ok(Status {
             liquid_temp: bytes[15] as f32 + bytes[16] as f32 / 10.,
             pump_speed: (bytes[18] as usize) << 8 | bytes[17] as usize,
             pump_rate: bytes[19],
             fan_speed: (bytes[24] as usize) << 8 | bytes[23] as usize,
             fan_rate: bytes[25],
-            firmware: (bytes_firm[17], bytes_firm[18], bytes_firm[19]),
-        }
+            firmware,
+            serial: info.and_then(|i| i.serial.clone()),
+            hardware_revision: info.map(|i| i.hardware_revision.clone()),
+            lcd_brightness,
+            lcd_orientation,
+        })
     }
 
-    fn get_handle(&mut self) -> Option<DeviceHandle<Context>> {
-        let mut context = rusb::Context::new().unwrap();
-        context.set_log_level(self.debug_level.into());
-        let device_handle = context.open_device_with_vid_pid(0x1e71, 0x3008)?;
+    pub fn firmware(&mut self) -> Result<(u8, u8, u8), KrakenError> {
+        self.supports(Feature::Firmware)?;
+        let bytes_firm = self.write_and_read_interface(&buff![0x10, 1; 64])?;
+        Ok((bytes_firm[17], bytes_firm[18], bytes_firm[19]))
+    }
 
-        device_handle.set_active_configuration(1).unwrap();
-        Some(device_handle)
+    /// brightness and orientation in degrees as reported by the lcd
+    pub fn lcd_info(&mut self) -> Result<(u8, u16), KrakenError> {
+        self.supports(Feature::Brightness)?;
+        let bytes = self.write_and_read_interface(&buff![0x30, 1; 64])?;
+        Ok((bytes[24], bytes[26] as u16 % 4 * 90))
     }
 
-    fn write_to_interrupt(&mut self, device_handle: &DeviceHandle<Context>, bytes: Vec<u8>) {
-        if device_handle.claim_interface(1).is_err() {
-            self.debug("Could not claim interrupt interface", DebugLevel::Error);
-            return;
-        }
-        let result = device_handle.write_interrupt(1, &bytes, Duration::from_millis(200));
-        if let Err(err) = result {
-            self.debug(
-                format!("Error writing to interface {err}"),
-                DebugLevel::Error,
-            );
-            return;
-        }
-        if device_handle.release_interface(1).is_err() {
-            self.debug("Could not release interface", DebugLevel::Error);
-        }
+    fn write_to_interrupt(&mut self, bytes: Vec<u8>) -> Result<(), KrakenError> {
+        self.transport
+            .write_interrupt(INTERRUPT_OUT, &bytes, Duration::from_millis(200))?;
+        Ok(())
     }
 
-    fn write_to_bulk(&mut self, device_handle: &DeviceHandle<Context>, bytes: &[u8]) {
-        if let Err(err) = device_handle.claim_interface(0) {
-            self.debug(
-                format!("Could not claim bulk interface {}", err),
-                DebugLevel::Error,
-            );
-        } else {
-            if device_handle
-                .write_bulk(2, bytes, Duration::from_millis(200))
-                .is_err()
-            {
-                self.debug("Could not write to bulk", DebugLevel::Error);
+    //large payloads are sent in chunks, each with a timeout for its size,
+    //and a chunk cut short is continued from where it stopped
+    fn write_to_bulk(&mut self, bytes: &[u8]) -> Result<(), KrakenError> {
+        let mut sent = 0;
+        let mut retries = 0;
+        while sent < bytes.len() {
+            let chunk = &bytes[sent..bytes.len().min(sent + BULK_CHUNK)];
+            let timeout = Duration::from_millis(200 + (chunk.len() / devices::BLOCK_SIZE) as u64);
+            //a timeout does not say how much was sent, so the frame cannot be resumed.
+            //only a short write gives a known offset to carry on from
+            let written = self.transport.write_bulk(BULK_OUT, chunk, timeout)?;
+            if written < chunk.len() {
+                retries += 1;
+                self.debug(
+                    format!(
+                        "Bulk write of {} bytes sent {written}, retry {retries} of {MAX_BULK_RETRIES}",
+                        chunk.len()
+                    ),
+                    DebugLevel::Warning,
+                );
+                if retries > MAX_BULK_RETRIES {
+                    return Err(KrakenError::Timeout);
+                }
             }
-            if device_handle.release_interface(0).is_err() {
-                self.debug("Could not release interface", DebugLevel::Error);
+            sent += written;
+            if let Some(progress) = self.progress.as_mut() {
+                progress(sent, bytes.len());
             }
         }
+        Ok(())
+    }
+
+    /// Called with bytes sent and total as each bulk chunk is written, e.g. to show a progress bar
+    pub fn on_progress(&mut self, progress: impl FnMut(usize, usize) + 'static) {
+        self.progress = Some(Box::new(progress));
     }
 
-    pub fn set_values_from_input(&mut self, input: &str, time: bool) {
+    pub fn set_values_from_input(&mut self, input: &str, time: bool) -> Result<(), KrakenError> {
         self.debug(format!("creating image from '{input}'"), DebugLevel::Info);
         //we strip any newlines from the input and trim ends
         let input = input.replace("\n", "").replace("\r", "");
@@ -412,9 +1050,32 @@ Firmware {}.{}.{}",
 
         let val = Input::new(input, time);
         let start = Instant::now();
-        let im = imagetools::image_from_input(val, &self.settings);
+        let im = imagetools::image_from_input(
+            val,
+            &self.settings,
+            self.profile.width,
+            self.render_rotation(),
+        );
         let elap1 = start.elapsed();
-        self.set_image_with_bytes(&im, false);
+
+        //values often stay the same between repeats, so only upload frames that changed,
+        //but refresh now and then in case the device lost the frame without us noticing
+        let mut hasher = DefaultHasher::new();
+        im.hash(&mut hasher);
+        let hash = hasher.finish();
+        let unchanged = self.shown_frame.is_some_and(|(shown, at, mode)| {
+            shown == hash && mode == self.lcd_mode && at.elapsed() < self.frame_refresh
+        });
+        if unchanged {
+            self.skipped_uploads += 1;
+            self.debug(
+                format!("Frame unchanged, skipped {} uploads", self.skipped_uploads),
+                DebugLevel::Debug,
+            );
+            return Ok(());
+        }
+        self.set_image_with_bytes(&im, false)?;
+        self.shown_frame = Some((hash, Instant::now(), self.lcd_mode));
         let elap2 = start.elapsed() - elap1;
 
         self.debug(
@@ -425,43 +1086,59 @@ Firmware {}.{}.{}",
             format!("setting image took {}ms", elap2.as_millis()),
             DebugLevel::Info,
         );
+        let stats = self.transport.stats();
+        if stats.opens == 0 {
+            return Ok(());
+        }
+        self.debug(
+            format!(
+                "usb session opened {} times, reused {} times, saving ~{}ms",
+                stats.opens,
+                stats.reuses,
+                stats.saved().as_millis()
+            ),
+            DebugLevel::Info,
+        );
+        Ok(())
+    }
+    fn write_and_read_interface(&mut self, input: &[u8]) -> Result<Vec<u8>, KrakenError> {
+        self.transport
+            .write_interrupt(INTERRUPT_OUT, input, Duration::from_millis(200))?;
+        self.read_reply(input)
     }
-    fn write_and_read_interface(&mut self, input: &[u8]) -> Vec<u8> {
-        let mut context = rusb::Context::new().unwrap();
-        context.set_log_level(self.debug_level.into());
-        let device_handle = context
-            .open_device_with_vid_pid(0x1e71, 0x3008)
-            .expect("Could not open kraken for bulk");
 
-        device_handle.set_active_configuration(1).unwrap();
-        device_handle
-            .claim_interface(1)
-            .expect("could not claim interface");
+    //wait for the device to report a command is done instead of pausing a fixed time
+    //no report means the command may not have been done, so is an error
+    fn write_and_wait(&mut self, input: Vec<u8>) -> Result<(), KrakenError> {
+        self.write_to_interrupt(input.clone())?;
+        self.read_reply(&input).map(|_| ())
+    }
 
-        let res = device_handle.write_interrupt(1, input, Duration::from_millis(200));
-        if let Err(e) = res {
-            self.debug(
-                format!("error writing to kraken {:?}", e),
-                DebugLevel::Error,
-            );
-        }
-        std::thread::sleep(Duration::from_millis(200));
+    //replies start with the command id + 1 and the same sub command
+    //anything else is a stale or unrelated report, so is skipped
+    fn read_reply(&mut self, input: &[u8]) -> Result<Vec<u8>, KrakenError> {
+        let expected = [input[0].wrapping_add(1), input[1]];
         let mut buf = [0u8; 64];
-        let res = device_handle.read_interrupt(129, &mut buf, Duration::from_millis(500));
-        if let Err(e) = res {
+        for _ in 0..MAX_READS {
+            let read = self.transport.read_interrupt(
+                INTERRUPT_IN,
+                &mut buf,
+                Duration::from_millis(500),
+            )?;
+            if read == buf.len() && buf[..2] == expected {
+                return Ok(buf.to_vec());
+            }
             self.debug(
-                format!("error reading from kraken {:?}", e),
-                DebugLevel::Error,
+                format!(
+                    "Skipping report {:02x?} of {read} bytes waiting for {expected:02x?}",
+                    &buf[..read.min(2)]
+                ),
+                DebugLevel::Debug,
             );
         }
-        let bytes = buf.into_iter().collect::<Vec<u8>>();
-
-        device_handle
-            .release_interface(1)
-            .expect("Could not release interface");
-        device_handle.reset().expect("Could not reset device");
-
-        bytes
+        Err(KrakenError::Protocol(format!(
+            "no {expected:02x?} reply after {MAX_READS} reads"
+        )))
     }
 
     pub(crate) fn reload_settings(&mut self) {
@@ -484,12 +1161,9 @@ Firmware {}.{}.{}",
                     DebugLevel::Info,
                 );
                 if Some(file) != self.settings.path {
-                    self.debug(
-                        format!("Reloading settings as file changed"),
-                        DebugLevel::Info,
-                    );
+                    self.debug("Reloading settings as file changed", DebugLevel::Info);
                     if let Ok(settings) = Settings::load() {
-                        self.settings = settings;
+                        self.replace_settings(settings);
                         return;
                     }
                 }
@@ -521,13 +1195,9 @@ Firmware {}.{}.{}",
                             DebugLevel::Info,
                         );
                     }
-                    self.debug(
-                        format!("Reloading settings as file time changed"),
-                        DebugLevel::Info,
-                    );
+                    self.debug("Reloading settings as file time changed", DebugLevel::Info);
                     if let Ok(settings) = Settings::load() {
-                        self.settings = settings;
-                        return;
+                        self.replace_settings(settings);
                     }
                 }
             }
@@ -537,4 +1207,386 @@ Firmware {}.{}.{}",
             ),
         }
     }
+
+    //swap in reloaded settings, applying orientation, curves and lighting again if they changed
+    fn replace_settings(&mut self, settings: Settings) {
+        settings.warn_problems();
+        let curves_changed = settings.pump_curve != self.settings.pump_curve
+            || settings.fan_curve != self.settings.fan_curve;
+        let lighting_changed = settings.ring_lighting != self.settings.ring_lighting
+            || settings.logo_lighting != self.settings.logo_lighting;
+        let orientation_changed = settings.orientation != self.settings.orientation;
+        self.settings = settings;
+        if let (true, Some(orientation)) = (orientation_changed, self.settings.orientation) {
+            self.debug("Orientation changed, applying", DebugLevel::Info);
+            if let Err(e) = self.set_orientation(orientation) {
+                self.debug(
+                    format!("Could not apply orientation {e}"),
+                    DebugLevel::Error,
+                );
+            }
+        }
+        if lighting_changed {
+            self.debug("Lighting changed, applying", DebugLevel::Info);
+            if let Err(e) = self.apply_lighting() {
+                self.debug(format!("Could not apply lighting {e}"), DebugLevel::Error);
+            }
+        }
+        if curves_changed {
+            self.debug("Curves changed, uploading", DebugLevel::Info);
+            if let Err(e) = self.apply_curves() {
+                self.debug(format!("Could not apply curves {e}"), DebugLevel::Error);
+            }
+        }
+    }
+}
+
+impl Drop for Manager {
+    //the shown bucket changes with every frame, so is only written once the run ends
+    fn drop(&mut self) {
+        if let Err(e) = self.buckets.save_shown() {
+            self.debug(
+                format!("Could not save bucket manifest. {e}"),
+                DebugLevel::Warning,
+            );
+        }
+    }
+}
+
+fn unknown(value: &Option<impl ToString>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::vec_init_then_push)]
+mod tests {
+    use super::*;
+    use crate::{protocol, transport::Kind};
+
+    fn manager(settings: Settings) -> (Manager, MockTransport) {
+        let mock = MockTransport::new();
+        (
+            Manager::mock(DebugLevel::None, settings, mock.clone()),
+            mock,
+        )
+    }
+
+    fn reply(bytes: &[u8]) -> [u8; 64] {
+        let mut reply = [0u8; 64];
+        reply[..bytes.len()].copy_from_slice(bytes);
+        reply
+    }
+
+    #[test]
+    fn brightness_keeps_configured_orientation() {
+        let settings = Settings {
+            orientation: Some(90),
+            ..Default::default()
+        };
+        let (mut manager, mock) = manager(settings);
+        manager.set_brightness(50).unwrap();
+        assert_eq!(
+            mock.interrupt_packets(),
+            vec![buff![0x30, 2, 1, 50, 0, 0, 1, 1; 64]]
+        );
+    }
+
+    #[test]
+    fn brightness_reads_orientation_from_device() {
+        let (mut manager, mock) = manager(Settings::default());
+        //lcd at 80%, rotated 270°
+        let mut info = reply(&[0x31, 0x01]);
+        info[24] = 80;
+        info[26] = 3;
+        mock.push_reply(info);
+        manager.set_brightness(20).unwrap();
+        assert_eq!(
+            mock.interrupt_packets(),
+            vec![buff![0x30, 1; 64], buff![0x30, 2, 1, 20, 0, 0, 1, 3; 64]]
+        );
+    }
+
+    #[test]
+    fn brightness_over_100_is_rejected() {
+        let (mut manager, mock) = manager(Settings::default());
+        assert!(matches!(
+            manager.set_brightness(101),
+            Err(KrakenError::Invalid(_))
+        ));
+        assert!(mock.packets().is_empty());
+    }
+
+    #[test]
+    fn pump_duty_is_raised_to_minimum() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_pump_duty(10).unwrap();
+        let mut expected = buff![0x72, 0x01, 0, 0; 64];
+        expected[4..4 + PROFILE_POINTS].fill(20);
+        assert_eq!(mock.interrupt_packets(), vec![expected]);
+    }
+
+    #[test]
+    fn fan_duty_is_sent_for_every_temperature() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_fan_duty(0).unwrap();
+        manager.set_fan_duty(100).unwrap();
+        let packets = mock.interrupt_packets();
+        assert_eq!(packets[0], buff![0x72, 0x02, 0, 0; 64]);
+        assert_eq!(packets[1][..4], [0x72, 0x02, 0, 0]);
+        assert!(packets[1][4..4 + PROFILE_POINTS].iter().all(|&d| d == 100));
+        assert!(packets[1][4 + PROFILE_POINTS..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn duty_over_100_is_rejected() {
+        let (mut manager, mock) = manager(Settings::default());
+        assert!(matches!(
+            manager.set_fan_duty(101),
+            Err(KrakenError::Invalid(_))
+        ));
+        assert!(matches!(
+            manager.set_pump_duty(101),
+            Err(KrakenError::Invalid(_))
+        ));
+        assert!(mock.packets().is_empty());
+    }
+
+    #[test]
+    fn upload_sets_up_bucket_then_bulk_then_shows_it() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_image_with_bytes(&[7; 3000], false).unwrap();
+
+        let packets = mock.packets();
+        //buckets are unknown at first, so all are cleared
+        for (i, packet) in packets[..16].iter().enumerate() {
+            assert_eq!(packet.data, buff![0x30, 4, i as u8; 64]);
+        }
+        let sequence = packets[16..]
+            .iter()
+            .map(|p| match p.kind {
+                Kind::Interrupt => p.data[..2].to_vec(),
+                Kind::Bulk => vec![],
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sequence,
+            vec![
+                vec![0x32, 2],
+                vec![0x32, 1],
+                vec![0x36, 1],
+                vec![],
+                vec![],
+                vec![0x36, 2],
+                vec![0x38, 1],
+            ]
+        );
+        assert_eq!(packets[17].data, buff![0x32, 1, 0, 1, 0, 0, 3, 0, 1; 64]);
+        assert_eq!(packets[22].data, buff![0x38, 1, 4, 0; 64]);
+
+        let bulk = mock.bulk_packets();
+        assert_eq!(bulk[0].len(), 512);
+        assert_eq!(bulk[0][..12], protocol::BULK_MAGIC);
+        assert_eq!(bulk[0][12], 2);
+        assert_eq!(bulk[0][16..20], 3000u32.to_le_bytes());
+        assert_eq!(bulk[1], vec![7; 3000]);
+        assert!(packets.iter().all(|p| match p.kind {
+            Kind::Interrupt => p.endpoint == INTERRUPT_OUT,
+            Kind::Bulk => p.endpoint == BULK_OUT,
+        }));
+    }
+
+    #[test]
+    fn bucket_uploads_do_not_overlap() {
+        let path =
+            std::env::temp_dir().join(format!("krakenctl-upload-{}.png", std::process::id()));
+        image::RgbaImage::new(8, 8).save(&path).unwrap();
+        let (mut manager, mock) = manager(Settings::default());
+        manager.upload_to_bucket(2, &path).unwrap();
+        manager.upload_to_bucket(3, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //buckets are unknown at first, so all are cleared once before the first upload
+        let interrupt = mock.interrupt_packets();
+        let clears = interrupt.iter().filter(|p| p[..2] == [0x30, 4]).count();
+        assert_eq!(clears, 16);
+        let setups = interrupt
+            .iter()
+            .filter(|p| p[..2] == [0x32, 1])
+            .collect::<Vec<_>>();
+        //a 320x320 rgba frame takes 400 blocks, so the second starts after the first
+        assert_eq!(setups[0][..9], [0x32, 1, 2, 3, 0, 0, 0x90, 1, 1]);
+        assert_eq!(setups[1][..9], [0x32, 1, 3, 4, 0x90, 1, 0x90, 1, 1]);
+    }
+
+    #[test]
+    fn frame_buckets_are_reserved() {
+        let (mut manager, mock) = manager(Settings::default());
+        for index in FRAME_BUCKETS {
+            assert!(matches!(
+                manager.upload_to_bucket(index, Path::new("logo.png")),
+                Err(KrakenError::Invalid(_))
+            ));
+            assert!(matches!(
+                manager.delete_bucket(index),
+                Err(KrakenError::Invalid(_))
+            ));
+        }
+        assert!(mock.packets().is_empty());
+        manager.show_bucket(1).unwrap();
+    }
+
+    #[test]
+    fn frames_alternate_between_buckets() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_image_with_bytes(&[1; 3000], false).unwrap();
+        mock.clear();
+        manager.set_image_with_bytes(&[2; 3000], false).unwrap();
+
+        let interrupt = mock.interrupt_packets();
+        //the second frame goes after the first in memory, as the first is on screen
+        assert_eq!(interrupt[1], buff![0x32, 1, 1, 2, 3, 0, 3, 0, 1; 64]);
+        assert_eq!(interrupt.last().unwrap(), &buff![0x38, 1, 4, 1; 64]);
+        mock.clear();
+        manager.set_image_with_bytes(&[3; 3000], false).unwrap();
+        assert_eq!(
+            mock.interrupt_packets().last().unwrap(),
+            &buff![0x38, 1, 4, 0; 64]
+        );
+    }
+
+    fn uploads(mock: &MockTransport) -> usize {
+        mock.interrupt_packets()
+            .iter()
+            .filter(|p| p[..2] == [0x36, 2])
+            .count()
+    }
+
+    #[test]
+    fn unchanged_values_are_not_uploaded() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_values_from_input("45°", false).unwrap();
+        manager.set_values_from_input("45°", false).unwrap();
+        assert_eq!(uploads(&mock), 1);
+        assert_eq!(manager.skipped_uploads, 1);
+        manager.set_values_from_input("46°", false).unwrap();
+        assert_eq!(uploads(&mock), 2);
+        assert_eq!(manager.skipped_uploads, 1);
+    }
+
+    #[test]
+    fn unchanged_values_are_uploaded_after_mode_change() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_values_from_input("45°", false).unwrap();
+        manager.set_liquid().unwrap();
+        manager.set_values_from_input("45°", false).unwrap();
+        assert_eq!(uploads(&mock), 2);
+        assert_eq!(manager.skipped_uploads, 0);
+    }
+
+    #[test]
+    fn unchanged_values_are_refreshed() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_frame_refresh(Duration::from_millis(500));
+        manager.set_values_from_input("45°", false).unwrap();
+        manager.set_values_from_input("45°", false).unwrap();
+        assert_eq!(uploads(&mock), 1);
+        std::thread::sleep(Duration::from_millis(600));
+        manager.set_values_from_input("45°", false).unwrap();
+        assert_eq!(uploads(&mock), 2);
+        assert_eq!(manager.skipped_uploads, 1);
+    }
+
+    #[test]
+    fn large_uploads_are_chunked() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager
+            .set_image_with_bytes(&vec![0; 3 * BULK_CHUNK + 100], true)
+            .unwrap();
+        let sizes = mock
+            .bulk_packets()
+            .iter()
+            .map(|p| p.len())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![512, BULK_CHUNK, BULK_CHUNK, BULK_CHUNK, 100]);
+        assert_eq!(mock.bulk_packets()[0][12], 1);
+    }
+
+    #[test]
+    fn unacknowledged_upload_is_not_shown() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_image_with_bytes(&[1; 3000], false).unwrap();
+        mock.clear();
+        mock.push_reply(reply(&[0x33, 0x01]));
+        mock.push_reply(reply(&[0x37, 0x01]));
+        for _ in 0..MAX_READS {
+            mock.push_reply(reply(&[0x75, 0x01]));
+        }
+        assert!(matches!(
+            manager.set_image_with_bytes(&[2; 3000], false),
+            Err(KrakenError::Protocol(_))
+        ));
+        assert!(mock.interrupt_packets().iter().all(|p| p[0] != 0x38));
+        assert_eq!(manager.buckets.get(1), None);
+        assert_eq!(manager.buckets.shown(), Some(0));
+    }
+
+    #[test]
+    fn reconnect_restores_frame() {
+        let (mut manager, mock) = manager(Settings::default());
+        manager.set_image_with_bytes(&[1; 3000], false).unwrap();
+        assert!(!manager.recover().unwrap());
+        mock.clear();
+        mock.reconnect();
+        assert!(manager.recover().unwrap());
+        //device memory is empty again, so buckets are cleared before the frame is uploaded
+        let interrupt = mock.interrupt_packets();
+        assert_eq!(interrupt[0], buff![0x30, 4, 0; 64]);
+        assert_eq!(mock.bulk_packets()[1], vec![1; 3000]);
+        assert_eq!(interrupt.last().unwrap(), &buff![0x38, 1, 4, 0; 64]);
+    }
+
+    #[test]
+    fn status_is_parsed_from_replies() {
+        let (mut manager, mock) = manager(Settings::default());
+        //a stale report before the status reply is skipped
+        mock.push_reply(reply(&[0x11, 0x01]));
+        let status = manager.query().unwrap();
+        assert_eq!(status.liquid_temp, 31.5);
+        assert_eq!(status.pump_speed, 2000);
+        assert_eq!(status.pump_rate, 60);
+        assert_eq!(status.fan_speed, 1200);
+        assert_eq!(status.fan_rate, 40);
+        assert_eq!(status.firmware, Some((2, 1, 0)));
+        assert_eq!(status.lcd_brightness, Some(80));
+        assert_eq!(status.lcd_orientation, Some(0));
+        assert_eq!(
+            mock.interrupt_packets(),
+            vec![buff![0x74, 1; 64], buff![0x10, 1; 64], buff![0x30, 1; 64]]
+        );
+    }
+
+    #[test]
+    fn status_without_firmware_is_still_reported() {
+        let (mut manager, mock) = manager(Settings::default());
+        mock.push_reply(protocol::canned_reply([0x74, 0x01]));
+        for _ in 0..MAX_READS {
+            mock.push_reply(reply(&[0x75, 0x01]));
+        }
+        let status = manager.query().unwrap();
+        assert_eq!(status.firmware, None);
+        assert_eq!(status.liquid_temp, 31.5);
+        assert_eq!(status.lcd_brightness, Some(80));
+    }
+
+    #[test]
+    fn missing_reply_is_an_error() {
+        let (mut manager, mock) = manager(Settings::default());
+        for _ in 0..MAX_READS {
+            mock.push_reply(reply(&[0x31, 0x01]));
+        }
+        assert!(matches!(manager.query(), Err(KrakenError::Protocol(_))));
+    }
 }
@@ -0,0 +1,135 @@
+use crate::{
+    protocol,
+    transport::{Kind, SessionStats, Transport},
+};
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, time::Duration};
+
+#[derive(Clone, Debug)]
+pub struct Packet {
+    pub kind: Kind,
+    pub endpoint: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MockState {
+    packets: Vec<Packet>,
+    replies: VecDeque<[u8; 64]>,
+    last_command: [u8; 2],
+    reconnects: usize,
+}
+
+/// In-memory stand in for a Kraken.
+/// Records every packet written and answers reads with scripted replies,
+/// falling back to a canned reply for the last command sent.
+/// Clones share the same state, so keep one to inspect after handing the other to the manager.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Rc<RefCell<MockState>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// queue a reply to be returned by the next read, before any canned reply
+    pub fn push_reply(&self, reply: [u8; 64]) {
+        self.state.borrow_mut().replies.push_back(reply);
+    }
+
+    pub fn packets(&self) -> Vec<Packet> {
+        self.state.borrow().packets.clone()
+    }
+
+    pub fn interrupt_packets(&self) -> Vec<Vec<u8>> {
+        self.state
+            .borrow()
+            .packets
+            .iter()
+            .filter(|p| p.kind == Kind::Interrupt)
+            .map(|p| p.data.clone())
+            .collect()
+    }
+
+    pub fn bulk_packets(&self) -> Vec<Vec<u8>> {
+        self.state
+            .borrow()
+            .packets
+            .iter()
+            .filter(|p| p.kind == Kind::Bulk)
+            .map(|p| p.data.clone())
+            .collect()
+    }
+
+    /// act as if the device re-enumerated and the session was opened again
+    pub fn reconnect(&self) {
+        self.state.borrow_mut().reconnects += 1;
+    }
+
+    pub fn clear(&self) {
+        self.state.borrow_mut().packets.clear();
+    }
+}
+
+impl Transport for MockTransport {
+    fn write_interrupt(
+        &mut self,
+        endpoint: u8,
+        bytes: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        if bytes.len() >= 2 {
+            state.last_command = [bytes[0], bytes[1]];
+        }
+        state.packets.push(Packet {
+            kind: Kind::Interrupt,
+            endpoint,
+            data: bytes.to_vec(),
+        });
+        Ok(bytes.len())
+    }
+
+    fn read_interrupt(
+        &mut self,
+        _endpoint: u8,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let reply = match state.replies.pop_front() {
+            Some(reply) => reply,
+            None => protocol::canned_reply(state.last_command),
+        };
+        let len = buf.len().min(reply.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        Ok(len)
+    }
+
+    fn write_bulk(
+        &mut self,
+        endpoint: u8,
+        bytes: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.state.borrow_mut().packets.push(Packet {
+            kind: Kind::Bulk,
+            endpoint,
+            data: bytes.to_vec(),
+        });
+        Ok(bytes.len())
+    }
+
+    fn reopen(&mut self) -> rusb::Result<()> {
+        self.reconnect();
+        Ok(())
+    }
+
+    fn stats(&self) -> SessionStats {
+        SessionStats {
+            reconnects: self.state.borrow().reconnects,
+            ..Default::default()
+        }
+    }
+}
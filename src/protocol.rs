@@ -0,0 +1,42 @@
+//start of the 512 byte header sent before every bulk upload
+pub const BULK_MAGIC: [u8; 12] = [
+    0x12, 0xfa, 0x01, 0xe8, 0xab, 0xcd, 0xef, 0x98, 0x76, 0x54, 0x32, 0x10,
+];
+
+/// Reply a Kraken gives to a command, with fixed readings, for backends without a device
+pub fn canned_reply(command: [u8; 2]) -> [u8; 64] {
+    let mut reply = [0u8; 64];
+    match command {
+        //status: 31.5°C liquid, pump 2000rpm at 60%, fan 1200rpm at 40%
+        [0x74, 0x01] => {
+            reply[0] = 0x75;
+            reply[1] = 0x01;
+            reply[15] = 31;
+            reply[16] = 5;
+            reply[17..19].copy_from_slice(&2000u16.to_le_bytes());
+            reply[19] = 60;
+            reply[23..25].copy_from_slice(&1200u16.to_le_bytes());
+            reply[25] = 40;
+        }
+        //firmware 2.1.0
+        [0x10, 0x01] => {
+            reply[0] = 0x11;
+            reply[1] = 0x01;
+            reply[17] = 2;
+            reply[18] = 1;
+            reply[19] = 0;
+        }
+        //lcd at 80% brightness, not rotated
+        [0x30, 0x01] => {
+            reply[0] = 0x31;
+            reply[1] = 0x01;
+            reply[24] = 80;
+            reply[26] = 0;
+        }
+        [a, b] => {
+            reply[0] = a.wrapping_add(1);
+            reply[1] = b;
+        }
+    }
+    reply
+}
@@ -0,0 +1,422 @@
+use crate::{
+    error::KrakenError,
+    managerrusb::DebugLevel,
+    transport::{DeviceInfo, Kind, SessionStats, Transport, INTERRUPT_IN},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+//recording file layout, all numbers little endian:
+//  MAGIC
+//  then records, each starting with a tag byte
+//  TAG_PAYLOAD: id u32, len u32, bytes          a bulk payload, stored once
+//  TAG_EVENT:   micros u64, kind u8, direction u8, endpoint u8, then
+//               DATA_INLINE: len u32, stored u32, bytes   trailing zeros trimmed
+//               DATA_PAYLOAD: id u32                      a payload stored earlier
+const MAGIC: &[u8; 8] = b"KRAKREC1";
+const TAG_PAYLOAD: u8 = 0;
+const TAG_EVENT: u8 = 1;
+const DATA_INLINE: u8 = 0;
+const DATA_PAYLOAD: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+/// One packet passed to or from the device
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// since the recording started
+    pub time: Duration,
+    pub kind: Kind,
+    pub direction: Direction,
+    pub endpoint: u8,
+    pub data: Vec<u8>,
+}
+
+/// Transport that writes every packet through it to a recording file
+pub struct Recorder {
+    inner: Box<dyn Transport>,
+    writer: Option<BufWriter<File>>,
+    start: Instant,
+    /// hash of each bulk payload already stored, to its id
+    payloads: HashMap<u64, u32>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, inner: Box<dyn Transport>) -> Result<Self, KrakenError> {
+        let mut writer =
+            BufWriter::new(File::create(path).map_err(|e| KrakenError::file(path, e))?);
+        writer
+            .write_all(MAGIC)
+            .map_err(|e| KrakenError::file(path, e))?;
+        Ok(Recorder {
+            inner,
+            writer: Some(writer),
+            start: Instant::now(),
+            payloads: HashMap::new(),
+        })
+    }
+
+    //bulk payloads repeat a lot when the same frame is sent again, so are only stored once
+    fn write_event(
+        &mut self,
+        kind: Kind,
+        direction: Direction,
+        endpoint: u8,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let micros = self.start.elapsed().as_micros() as u64;
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        let payload = if kind == Kind::Bulk {
+            let mut hasher = DefaultHasher::new();
+            data.hash(&mut hasher);
+            let hash = hasher.finish();
+            let next = self.payloads.len() as u32;
+            let id = *self.payloads.entry(hash).or_insert(next);
+            if id == next {
+                writer.write_all(&[TAG_PAYLOAD])?;
+                writer.write_all(&id.to_le_bytes())?;
+                writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                writer.write_all(data)?;
+            }
+            Some(id)
+        } else {
+            None
+        };
+
+        writer.write_all(&[TAG_EVENT])?;
+        writer.write_all(&micros.to_le_bytes())?;
+        writer.write_all(&[kind as u8, direction as u8, endpoint])?;
+        match payload {
+            Some(id) => {
+                writer.write_all(&[DATA_PAYLOAD])?;
+                writer.write_all(&id.to_le_bytes())?;
+            }
+            None => {
+                let stored = data.len() - data.iter().rev().take_while(|&&b| b == 0).count();
+                writer.write_all(&[DATA_INLINE])?;
+                writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                writer.write_all(&(stored as u32).to_le_bytes())?;
+                writer.write_all(&data[..stored])?;
+            }
+        }
+        //flushed every time, as a killed process does not drop the recorder
+        writer.flush()
+    }
+
+    //recording problems should not stop the device being used, so stop recording instead
+    fn record(&mut self, kind: Kind, direction: Direction, endpoint: u8, data: &[u8]) {
+        if let Err(e) = self.write_event(kind, direction, endpoint, data) {
+            eprintln!("Could not write recording, stopping: {e}");
+            self.writer = None;
+        }
+    }
+}
+
+impl Transport for Recorder {
+    fn write_interrupt(
+        &mut self,
+        endpoint: u8,
+        bytes: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let written = self.inner.write_interrupt(endpoint, bytes, timeout)?;
+        self.record(Kind::Interrupt, Direction::Out, endpoint, bytes);
+        Ok(written)
+    }
+
+    fn read_interrupt(
+        &mut self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let read = self.inner.read_interrupt(endpoint, buf, timeout)?;
+        self.record(Kind::Interrupt, Direction::In, endpoint, &buf[..read]);
+        Ok(read)
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, bytes: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        let written = self.inner.write_bulk(endpoint, bytes, timeout)?;
+        self.record(Kind::Bulk, Direction::Out, endpoint, bytes);
+        Ok(written)
+    }
+
+    fn reopen(&mut self) -> rusb::Result<()> {
+        self.inner.reopen()
+    }
+
+    fn stats(&self) -> SessionStats {
+        self.inner.stats()
+    }
+
+    fn info(&self) -> Option<&DeviceInfo> {
+        self.inner.info()
+    }
+
+    fn detached_drivers(&self) -> Vec<u8> {
+        self.inner.detached_drivers()
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> std::io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> std::io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> std::io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_bytes(reader: &mut impl Read, len: u32) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read all events of a recording, with bulk payloads filled back in
+pub fn load(path: &Path) -> Result<Vec<Event>, KrakenError> {
+    let invalid = |e: &str| KrakenError::Invalid(format!("{}: {e}", path.display()));
+    let mut reader = BufReader::new(File::open(path).map_err(|e| KrakenError::file(path, e))?);
+    let mut magic = [0u8; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|e| KrakenError::file(path, e))?;
+    if &magic != MAGIC {
+        return Err(invalid("not a krakenctl recording"));
+    }
+
+    let mut payloads: HashMap<u32, Vec<u8>> = HashMap::new();
+    let mut events = vec![];
+    //a recording cut short by a crash ends part way through a record, so keep what was read
+    while let Ok(tag) = read_u8(&mut reader) {
+        let result = match tag {
+            TAG_PAYLOAD => read_payload(&mut reader).map(|(id, data)| {
+                payloads.insert(id, data);
+            }),
+            TAG_EVENT => read_event(&mut reader, &payloads).map(|event| events.push(event)),
+            _ => return Err(invalid(&format!("unknown record {tag}"))),
+        };
+        match result {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                return Err(invalid(&e.to_string()))
+            }
+            Err(e) => return Err(KrakenError::file(path, e)),
+        }
+    }
+    Ok(events)
+}
+
+fn read_payload(reader: &mut impl Read) -> std::io::Result<(u32, Vec<u8>)> {
+    let id = read_u32(reader)?;
+    let len = read_u32(reader)?;
+    Ok((id, read_bytes(reader, len)?))
+}
+
+fn read_event(reader: &mut impl Read, payloads: &HashMap<u32, Vec<u8>>) -> std::io::Result<Event> {
+    let time = Duration::from_micros(read_u64(reader)?);
+    let kind = match read_u8(reader)? {
+        0 => Kind::Interrupt,
+        _ => Kind::Bulk,
+    };
+    let direction = match read_u8(reader)? {
+        0 => Direction::Out,
+        _ => Direction::In,
+    };
+    let endpoint = read_u8(reader)?;
+    let data = match read_u8(reader)? {
+        DATA_PAYLOAD => payloads
+            .get(&read_u32(reader)?)
+            .ok_or(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "event refers to a missing payload",
+            ))?
+            .clone(),
+        _ => {
+            let len = read_u32(reader)?;
+            let stored = read_u32(reader)?;
+            let mut data = read_bytes(reader, stored)?;
+            data.resize(len as usize, 0);
+            data
+        }
+    };
+    Ok(Event {
+        time,
+        kind,
+        direction,
+        endpoint,
+        data,
+    })
+}
+
+/// Send the packets of a recording again, keeping the original timing.
+/// Replies are read where the recording read one, and differences reported
+pub fn replay(
+    transport: &mut dyn Transport,
+    events: &[Event],
+    debug_level: DebugLevel,
+) -> Result<(), KrakenError> {
+    let timeout = Duration::from_millis(500);
+    let start = Instant::now();
+    for event in events {
+        if let Some(wait) = event.time.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        if debug_level >= DebugLevel::Debug {
+            println!(
+                "{:>10.3}s {:?} {:?} ep {} {:02x?}",
+                event.time.as_secs_f64(),
+                event.kind,
+                event.direction,
+                event.endpoint,
+                &event.data[..event.data.len().min(8)]
+            );
+        }
+        match (event.kind, event.direction) {
+            (Kind::Interrupt, Direction::Out) => {
+                transport.write_interrupt(event.endpoint, &event.data, timeout)?;
+            }
+            (Kind::Bulk, Direction::Out) => {
+                transport.write_bulk(event.endpoint, &event.data, timeout)?;
+            }
+            (_, Direction::In) => {
+                let mut buf = [0u8; 64];
+                match transport.read_interrupt(INTERRUPT_IN, &mut buf, timeout) {
+                    Ok(read) if buf[..read.min(2)] != event.data[..event.data.len().min(2)] => {
+                        if debug_level >= DebugLevel::Warning {
+                            println!(
+                                "reply {:02x?} differs from recorded {:02x?}",
+                                &buf[..read.min(2)],
+                                &event.data[..event.data.len().min(2)]
+                            );
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(e) => eprintln!("no reply where one was recorded: {e}"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock::MockTransport, transport::INTERRUPT_OUT};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("krakenctl-{name}-{}.rec", std::process::id()))
+    }
+
+    //status request and reply, then the same frame twice
+    fn record_session(path: &Path) -> MockTransport {
+        let mock = MockTransport::new();
+        let mut recorder = Recorder::create(path, Box::new(mock.clone())).unwrap();
+        let mut status = [0u8; 64];
+        status[..2].copy_from_slice(&[0x74, 0x01]);
+        let timeout = Duration::from_millis(200);
+        recorder
+            .write_interrupt(INTERRUPT_OUT, &status, timeout)
+            .unwrap();
+        let mut buf = [0u8; 64];
+        recorder
+            .read_interrupt(INTERRUPT_IN, &mut buf, timeout)
+            .unwrap();
+        let frame = (0..100_000).map(|i| i as u8).collect::<Vec<u8>>();
+        recorder.write_bulk(2, &frame, timeout).unwrap();
+        recorder.write_bulk(2, &frame, timeout).unwrap();
+        mock
+    }
+
+    #[test]
+    fn recording_round_trips() {
+        let path = temp_path("round-trip");
+        let mock = record_session(&path);
+        let size = std::fs::metadata(&path).unwrap().len();
+        let events = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        //the repeated frame is only stored once
+        assert!(size < 110_000, "recording is {size} bytes");
+        assert_eq!(events.len(), 4);
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.kind, e.direction, e.endpoint))
+                .collect::<Vec<_>>(),
+            vec![
+                (Kind::Interrupt, Direction::Out, INTERRUPT_OUT),
+                (Kind::Interrupt, Direction::In, INTERRUPT_IN),
+                (Kind::Bulk, Direction::Out, 2),
+                (Kind::Bulk, Direction::Out, 2),
+            ]
+        );
+        let written = mock.packets();
+        assert_eq!(events[0].data, written[0].data);
+        assert_eq!(events[1].data[..2], [0x75, 0x01]);
+        assert_eq!(events[1].data.len(), 64);
+        assert_eq!(events[2].data, written[1].data);
+        assert_eq!(events[3].data, written[2].data);
+    }
+
+    #[test]
+    fn replay_sends_recorded_packets() {
+        let path = temp_path("replay");
+        let recorded = record_session(&path);
+        let events = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut replayed = MockTransport::new();
+        replay(&mut replayed, &events, DebugLevel::None).unwrap();
+        let data = |mock: &MockTransport| {
+            mock.packets()
+                .into_iter()
+                .map(|p| (p.kind, p.data))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(data(&replayed), data(&recorded));
+    }
+
+    #[test]
+    fn truncated_recording_keeps_complete_events() {
+        let path = temp_path("truncated");
+        record_session(&path);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        let events = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let path = temp_path("not-a-recording");
+        std::fs::write(&path, b"PNG and more").unwrap();
+        let result = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(KrakenError::Invalid(_))));
+    }
+}
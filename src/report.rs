@@ -0,0 +1,190 @@
+use crate::managerrusb::Status;
+use chrono::{DateTime, Local};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format '{s}', expected text, json or csv")),
+        }
+    }
+}
+
+pub const CSV_HEADER: &str = "time,liquid_temp,fan_rpm,fan_duty,pump_rpm,pump_duty,firmware,serial,hardware_revision,lcd_brightness,lcd_orientation";
+
+fn firmware(status: &Status) -> Option<String> {
+    status.firmware.map(|(a, b, c)| format!("{a}.{b}.{c}"))
+}
+
+/// quoted and escaped json string
+pub fn json_string(string: &str) -> String {
+    let mut out = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+//json value, or null when unknown
+pub fn json_or_null<T>(value: &Option<T>, func: impl Fn(&T) -> String) -> String {
+    value.as_ref().map(func).unwrap_or("null".to_string())
+}
+
+/// one line of json per sample, so it can be streamed
+pub fn json(status: &Status, time: DateTime<Local>) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"liquid_temp\":{},\"fan_rpm\":{},\"fan_duty\":{},\"pump_rpm\":{},\"pump_duty\":{},\"firmware\":{},\"serial\":{},\"hardware_revision\":{},\"lcd_brightness\":{},\"lcd_orientation\":{}}}",
+        time.to_rfc3339(),
+        status.liquid_temp,
+        status.fan_speed,
+        status.fan_rate,
+        status.pump_speed,
+        status.pump_rate,
+        json_or_null(&firmware(status), |f| json_string(f)),
+        json_or_null(&status.serial, |s| json_string(s)),
+        json_or_null(&status.hardware_revision, |s| json_string(s)),
+        json_or_null(&status.lcd_brightness, |b| b.to_string()),
+        json_or_null(&status.lcd_orientation, |o| o.to_string()),
+    )
+}
+
+//csv field, empty when unknown, quoted if it holds a separator or quote
+fn csv_field(value: &Option<impl ToString>) -> String {
+    let field = value.as_ref().map(|v| v.to_string()).unwrap_or_default();
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+pub fn csv(status: &Status, time: DateTime<Local>) -> String {
+    format!(
+        "{},{},{},{},{},{},{},{},{},{},{}",
+        time.to_rfc3339(),
+        status.liquid_temp,
+        status.fan_speed,
+        status.fan_rate,
+        status.pump_speed,
+        status.pump_rate,
+        csv_field(&firmware(status)),
+        csv_field(&status.serial),
+        csv_field(&status.hardware_revision),
+        csv_field(&status.lcd_brightness),
+        csv_field(&status.lcd_orientation),
+    )
+}
+
+/// progress of an upload, redrawn in place on a terminal
+pub fn progress_bar(sent: usize, total: usize) -> String {
+    const WIDTH: usize = 30;
+    let filled = (sent * WIDTH).checked_div(total).unwrap_or(WIDTH);
+    format!(
+        "\r[{}{}] {:>3}% {}/{} KiB",
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        (sent * 100).checked_div(total).unwrap_or(100),
+        sent / 1024,
+        total / 1024
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn status(serial: &str) -> Status {
+        Status {
+            liquid_temp: 31.5,
+            pump_speed: 2000,
+            fan_speed: 1200,
+            pump_rate: 60,
+            fan_rate: 40,
+            firmware: Some((2, 1, 0)),
+            serial: Some(serial.to_string()),
+            hardware_revision: None,
+            lcd_brightness: Some(80),
+            lcd_orientation: None,
+        }
+    }
+
+    fn time() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn json_string_escapes_quotes_backslashes_and_controls() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(json_string("tab\tend"), r#""tab\u0009end""#);
+    }
+
+    #[test]
+    fn json_record_escapes_serial() {
+        let json = json(&status(r#"AB"12\3"#), time());
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"time":"{}","liquid_temp":31.5,"fan_rpm":1200,"fan_duty":40,"pump_rpm":2000,"pump_duty":60,"firmware":"2.1.0","serial":"AB\"12\\3","hardware_revision":null,"lcd_brightness":80,"lcd_orientation":null}}"#,
+                time().to_rfc3339()
+            )
+        );
+    }
+
+    #[test]
+    fn csv_record_quotes_serial() {
+        let csv = csv(&status(r#"AB"1,2\3"#), time());
+        assert_eq!(
+            csv,
+            format!(
+                r#"{},31.5,1200,40,2000,60,2.1.0,"AB""1,2\3",,80,"#,
+                time().to_rfc3339()
+            )
+        );
+        assert_eq!(
+            csv.split(',').count(),
+            CSV_HEADER.split(',').count() + 1,
+            "only the quoted comma is extra"
+        );
+    }
+
+    #[test]
+    fn unknown_firmware_is_null_or_empty() {
+        let status = Status {
+            firmware: None,
+            ..status("A1")
+        };
+        assert!(json(&status, time()).contains(r#""firmware":null,"serial":"A1""#));
+        assert!(csv(&status, time()).contains(",60,,A1,"));
+    }
+
+    #[test]
+    fn progress_bar_fills() {
+        assert!(progress_bar(0, 2048).ends_with("  0% 0/2 KiB"));
+        assert!(progress_bar(1024, 2048).contains(&format!(
+            "[{}{}]",
+            "#".repeat(15),
+            " ".repeat(15)
+        )));
+        assert!(progress_bar(0, 0).ends_with("100% 0/0 KiB"));
+    }
+}
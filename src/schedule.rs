@@ -0,0 +1,95 @@
+use chrono::NaiveTime;
+
+/// Lcd brightness by time of day, each brightness lasting until the next time
+#[derive(Clone, Debug, PartialEq)]
+pub struct BrightnessSchedule {
+    /// sorted by time
+    points: Vec<(NaiveTime, u8)>,
+}
+
+impl BrightnessSchedule {
+    /// parse from the config format time brightness,time brightness e.g. 08:00 80,22:00 20
+    pub fn parse(string: &str) -> Result<BrightnessSchedule, String> {
+        let mut points = vec![];
+        for point in string
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+        {
+            let (time, brightness) = point.split_once(' ').ok_or(format!(
+                "schedule entry '{point}' should be HH:MM brightness"
+            ))?;
+            let time = NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time in '{point}'"))?;
+            let brightness = brightness
+                .trim()
+                .trim_end_matches('%')
+                .parse::<u8>()
+                .map_err(|_| format!("invalid brightness in '{point}'"))?;
+            if brightness > 100 {
+                return Err(format!("brightness in '{point}' is over 100"));
+            }
+            points.push((time, brightness));
+        }
+        if points.is_empty() {
+            return Err("schedule has no entries".to_string());
+        }
+        points.sort_by_key(|p| p.0);
+        Ok(BrightnessSchedule { points })
+    }
+
+    /// brightness set by the latest entry at or before time, which before the first entry
+    /// of the day is the last entry of the day before
+    pub fn brightness_at(&self, time: NaiveTime) -> u8 {
+        self.points
+            .iter()
+            .rev()
+            .find(|p| p.0 <= time)
+            .unwrap_or(&self.points[self.points.len() - 1])
+            .1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn brightness_lasts_until_next_entry() {
+        let schedule = BrightnessSchedule::parse("08:00 80,22:00 20%").unwrap();
+        assert_eq!(schedule.brightness_at(at("08:00")), 80);
+        assert_eq!(schedule.brightness_at(at("21:59")), 80);
+        assert_eq!(schedule.brightness_at(at("22:00")), 20);
+    }
+
+    #[test]
+    fn brightness_wraps_past_midnight() {
+        //entries out of order, with one after midnight
+        let schedule = BrightnessSchedule::parse("08:00 80, 22:00 20, 01:00 0").unwrap();
+        assert_eq!(schedule.brightness_at(at("23:59")), 20);
+        assert_eq!(schedule.brightness_at(at("00:00")), 20);
+        assert_eq!(schedule.brightness_at(at("00:30")), 20);
+        assert_eq!(schedule.brightness_at(at("01:00")), 0);
+        assert_eq!(schedule.brightness_at(at("07:59")), 0);
+        assert_eq!(schedule.brightness_at(at("08:00")), 80);
+    }
+
+    #[test]
+    fn single_entry_lasts_all_day() {
+        let schedule = BrightnessSchedule::parse("12:00 50").unwrap();
+        assert_eq!(schedule.brightness_at(at("00:00")), 50);
+        assert_eq!(schedule.brightness_at(at("23:59")), 50);
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected() {
+        assert!(BrightnessSchedule::parse("").is_err());
+        assert!(BrightnessSchedule::parse("08:00").is_err());
+        assert!(BrightnessSchedule::parse("25:00 50").is_err());
+        assert!(BrightnessSchedule::parse("08:00 101").is_err());
+    }
+}
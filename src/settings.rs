@@ -1,6 +1,10 @@
+use crate::cooling::Curve;
+use crate::lighting::{LightChannel, Lighting};
+use crate::schedule::BrightnessSchedule;
 use image::Rgba;
 use std::{
     error::Error,
+    fmt::Display,
     fs::File,
     io::{BufReader, Read},
     path::PathBuf,
@@ -17,8 +21,18 @@ pub struct Settings {
     pub left_title: Rgba<u8>,
     pub right_title: Rgba<u8>,
     pub show_time: bool,
+    /// lcd rotation in degrees clockwise, None leaves the device as it is
+    pub orientation: Option<u16>,
+    pub pump_curve: Option<Curve>,
+    pub fan_curve: Option<Curve>,
+    /// lcd brightness by time of day, applied while repeating
+    pub brightness_schedule: Option<BrightnessSchedule>,
+    pub ring_lighting: Option<Lighting>,
+    pub logo_lighting: Option<Lighting>,
     pub loaded: SystemTime,
     pub path: Option<PathBuf>,
+    /// values in the file that were invalid and ignored
+    pub problems: Vec<String>,
 }
 
 impl Default for Settings {
@@ -32,8 +46,15 @@ impl Default for Settings {
             left_title: Rgba([120, 120, 255, 255]),
             right_title: Rgba([120, 120, 255, 255]),
             show_time: false,
+            orientation: None,
+            pump_curve: None,
+            fan_curve: None,
+            brightness_schedule: None,
+            ring_lighting: None,
+            logo_lighting: None,
             loaded: SystemTime::UNIX_EPOCH,
             path: None,
+            problems: vec![],
         }
     }
 }
@@ -77,6 +98,10 @@ impl Settings {
             ..Default::default()
         };
 
+        //lighting is made of several entries per channel, so collect them first
+        let mut ring: [Option<&str>; 3] = [None; 3];
+        let mut logo: [Option<&str>; 3] = [None; 3];
+
         for (left, right) in lines {
             match (left, right) {
                 ("left_bar", right) => settings.left_bar = string_to_rgba(right),
@@ -87,23 +112,78 @@ impl Settings {
                 ("right_title", right) => settings.right_title = string_to_rgba(right),
                 ("time", right) => settings.time = string_to_rgba(right),
                 ("show_time", "true") => settings.show_time = true,
+                ("orientation", right) => {
+                    settings.orientation = settings.check(left, parse_orientation(right))
+                }
+                ("pump_curve", right) => {
+                    settings.pump_curve = settings.check(left, Curve::parse(right))
+                }
+                ("fan_curve", right) => {
+                    settings.fan_curve = settings.check(left, Curve::parse(right))
+                }
+                ("brightness_schedule", right) => {
+                    settings.brightness_schedule =
+                        settings.check(left, BrightnessSchedule::parse(right))
+                }
+                ("ring_mode", right) => ring[0] = Some(right),
+                ("ring_colors", right) => ring[1] = Some(right),
+                ("ring_speed", right) => ring[2] = Some(right),
+                ("logo_mode", right) => logo[0] = Some(right),
+                ("logo_colors", right) => logo[1] = Some(right),
+                ("logo_speed", right) => logo[2] = Some(right),
 
                 _ => (),
             }
         }
+        if let [Some(mode), colors, speed] = ring {
+            let lighting = Lighting::from_config(LightChannel::Ring, mode, colors, speed);
+            settings.ring_lighting = settings.check("ring lighting", lighting);
+        }
+        if let [Some(mode), colors, speed] = logo {
+            let lighting = Lighting::from_config(LightChannel::Logo, mode, colors, speed);
+            settings.logo_lighting = settings.check("logo lighting", lighting);
+        }
         Ok(settings)
     }
 
+    //keep a valid value, or note why it was ignored
+    fn check<T, E: Display>(&mut self, key: &str, value: Result<T, E>) -> Option<T> {
+        value
+            .inspect_err(|e| self.problems.push(format!("{key}: {e}")))
+            .ok()
+    }
+
+    /// invalid values change what the device does, so always say they were ignored
+    pub fn warn_problems(&self) {
+        for problem in &self.problems {
+            eprintln!("Ignoring invalid config value {problem}");
+        }
+    }
+
     pub(crate) fn modified_time() -> Result<SystemTime, Box<dyn Error>> {
         let file = Settings::get_file()?;
         Ok(file.metadata()?.modified()?)
     }
 }
 
+/// one of 0, 90, 180 or 270 degrees
+pub(crate) fn parse_orientation(string: &str) -> Result<u16, String> {
+    match string.trim().trim_end_matches('°').parse::<u16>() {
+        Ok(deg @ (0 | 90 | 180 | 270)) => Ok(deg),
+        _ => Err(format!(
+            "orientation '{string}' should be 0, 90, 180 or 270"
+        )),
+    }
+}
+
 fn string_to_rgba(string: &str) -> Rgba<u8> {
-    let default_colour = Rgba([255, 0, 0, 255]);
+    parse_rgba(string).unwrap_or(Rgba([255, 0, 0, 255]))
+}
+
+/// parse a colour in the format #rrggbb
+pub(crate) fn parse_rgba(string: &str) -> Option<Rgba<u8>> {
     if string.len() < 7 || string.chars().next().unwrap_or_default() != '#' {
-        return default_colour;
+        return None;
     }
     let mut vec = vec![];
     for item in string
@@ -119,8 +199,8 @@ fn string_to_rgba(string: &str) -> Rgba<u8> {
         }
     }
     if vec.len() == 3 {
-        Rgba([vec[0], vec[1], vec[2], 255])
+        Some(Rgba([vec[0], vec[1], vec[2], 255]))
     } else {
-        default_colour
+        None
     }
 }
@@ -0,0 +1,190 @@
+use crate::{
+    devices::{self, NZXT_VID},
+    error::KrakenError,
+    transport::{self, DeviceInfo},
+};
+use rusb::UsbContext;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const RULES_DIR: &str = "/etc/udev/rules.d";
+pub const RULES_FILE: &str = "99-kraken.rules";
+
+/// udev rules giving the logged in user and the plugdev group access to every supported device
+pub fn udev_rules() -> String {
+    let mut rules = String::from("# generated by krakenctl setup permissions\n");
+    for device in devices::DEVICES {
+        rules.push_str(&format!("# {}\n", device.name));
+        rules.push_str(&format!(
+            "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n",
+            device.vid, device.pid
+        ));
+    }
+    rules
+}
+
+/// Write the udev rules into dir, or the system rules directory if none given
+pub fn permissions(dir: Option<&Path>) -> Result<(), KrakenError> {
+    let dir = dir.unwrap_or(Path::new(RULES_DIR));
+    let file_error = |e: std::io::Error| {
+        let hint = match e.kind() {
+            std::io::ErrorKind::PermissionDenied => ", try again with sudo",
+            _ => "",
+        };
+        KrakenError::file(dir, format!("{e}{hint}"))
+    };
+    fs::create_dir_all(dir).map_err(file_error)?;
+    let path = dir.join(RULES_FILE);
+    fs::write(&path, udev_rules()).map_err(file_error)?;
+    println!("Wrote {}", path.display());
+    if dir == Path::new(RULES_DIR) {
+        println!("Run 'sudo udevadm control --reload-rules && sudo udevadm trigger', then replug the cooler or reboot");
+    }
+    Ok(())
+}
+
+/// Explain why a device could not be opened, and what to do about it
+pub fn diagnose(error: &KrakenError) -> Option<String> {
+    match error {
+        KrakenError::NotFound => Some(diagnose_missing()),
+        KrakenError::PermissionDenied => Some(diagnose_permissions()),
+        KrakenError::Busy => Some(diagnose_busy()),
+        _ => None,
+    }
+}
+
+fn diagnose_missing() -> String {
+    //nzxt devices that are not in the registry, these need no permissions to list
+    let unsupported = rusb::Context::new()
+        .and_then(|c| c.devices())
+        .map(|list| {
+            list.iter()
+                .filter_map(|d| d.device_descriptor().ok())
+                .filter(|d| d.vendor_id() == NZXT_VID)
+                .filter(|d| devices::find(d.vendor_id(), d.product_id()).is_none())
+                .map(|d| format!("{:04x}:{:04x}", d.vendor_id(), d.product_id()))
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+    if unsupported.is_empty() {
+        "No supported Kraken is connected. Check the cooler's usb cable is plugged into a motherboard usb header".to_string()
+    } else {
+        format!(
+            "Found NZXT device(s) {} which are not supported. See the supported devices in the README",
+            unsupported.join(", ")
+        )
+    }
+}
+
+//device node that libusb opens
+fn device_node(info: &DeviceInfo) -> PathBuf {
+    PathBuf::from(format!("/dev/bus/usb/{:03}/{:03}", info.bus, info.address))
+}
+
+fn rules_installed(pid: u16) -> bool {
+    let pid = format!("{pid:04x}");
+    fs::read_dir(RULES_DIR)
+        .map(|dir| {
+            dir.flatten().any(|entry| {
+                fs::read_to_string(entry.path())
+                    .map(|rules| rules.contains(&pid) && rules.contains("1e71"))
+                    .unwrap_or_default()
+            })
+        })
+        .unwrap_or_default()
+}
+
+fn diagnose_permissions() -> String {
+    let mut lines = vec!["No permission to open the device.".to_string()];
+    for info in transport::list_devices().unwrap_or_default() {
+        let node = device_node(&info);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Ok(meta) = fs::metadata(&node) {
+                lines.push(format!(
+                    "{} is {} with mode {:o}",
+                    node.display(),
+                    info.profile.name,
+                    meta.permissions().mode() & 0o777
+                ));
+            }
+        }
+        if !rules_installed(info.profile.pid) {
+            lines.push(format!(
+                "No udev rule in {RULES_DIR} for {}",
+                info.profile.name
+            ));
+        }
+    }
+    lines.push(
+        "Run 'sudo krakenctl setup permissions' to install udev rules, or run krakenctl as root"
+            .to_string(),
+    );
+    lines.join("\n")
+}
+
+//kernel driver bound to an interface according to sysfs, usbfs means another program
+fn interface_driver(info: &DeviceInfo, interface: u8) -> Option<String> {
+    let link = format!(
+        "/sys/bus/usb/devices/{}:1.{interface}/driver",
+        info.port_string()
+    );
+    let target = fs::read_link(link).ok()?;
+    Some(target.file_name()?.to_string_lossy().to_string())
+}
+
+fn diagnose_busy() -> String {
+    let mut lines = vec!["The device is in use.".to_string()];
+    for info in transport::list_devices().unwrap_or_default() {
+        for i in [0, 1] {
+            match interface_driver(&info, i).as_deref() {
+                Some("usbfs") => lines.push(format!(
+                    "{} interface {i} is claimed by another program, e.g. liquidctl, a virtual machine or another krakenctl",
+                    info.profile.name
+                )),
+                Some(driver) => lines.push(format!(
+                    "{} interface {i} is owned by the {driver} kernel driver",
+                    info.profile.name
+                )),
+                None => (),
+            }
+        }
+    }
+    lines.push("Stop the other program and try again".to_string());
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permissions_writes_rules_for_every_device() {
+        //a directory that does not exist yet, which permissions creates
+        let dir = std::env::temp_dir().join(format!("krakenctl-rules-{}", std::process::id()));
+        permissions(Some(&dir.join("rules.d"))).unwrap();
+        let rules = fs::read_to_string(dir.join("rules.d").join(RULES_FILE)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rules, udev_rules());
+        for device in devices::DEVICES {
+            assert!(rules.contains(&format!(
+                "ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\"",
+                device.vid, device.pid
+            )));
+        }
+    }
+
+    #[test]
+    fn permissions_reports_unwritable_dir() {
+        //a file where the directory should be
+        let file = std::env::temp_dir().join(format!("krakenctl-not-a-dir-{}", std::process::id()));
+        fs::write(&file, "").unwrap();
+        let result = permissions(Some(&file));
+        fs::remove_file(&file).unwrap();
+        assert!(matches!(result, Err(KrakenError::File(_))));
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    devices::{DeviceProfile, BLOCK_SIZE},
+    error::KrakenError,
+    imagetools,
+    input::Input,
+    managerrusb::LcdMode,
+    protocol::{self, BULK_MAGIC},
+    settings::Settings,
+    transport::Transport,
+};
+use image::{
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    AnimationDecoder, DynamicImage, Frame,
+};
+use std::{cell::RefCell, io::Cursor, path::Path, rc::Rc, time::Duration};
+
+//liquid temperature the simulated liquid screen shows, the same as the canned status reply
+const LIQUID_TEMP: &str = "31.5°";
+
+/// A bucket set up with 0x32 0x01
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    /// start block in image memory
+    pub start: u16,
+    pub blocks: u16,
+    /// payload of the last finished upload, and whether it is a gif
+    pub content: Option<(Vec<u8>, bool)>,
+}
+
+//a bulk upload between 0x36 0x01 and 0x36 0x02
+struct Upload {
+    bucket: u8,
+    /// type and size from the bulk header, once it arrived
+    header: Option<(bool, usize)>,
+    data: Vec<u8>,
+}
+
+struct SimState {
+    profile: &'static DeviceProfile,
+    buckets: Vec<Option<Bucket>>,
+    mode: LcdMode,
+    brightness: u8,
+    orientation: u16,
+    upload: Option<Upload>,
+    last_command: [u8; 2],
+    problems: Vec<String>,
+}
+
+/// Software Kraken that keeps the state the real device would,
+/// so what the lcd shows can be saved as an image.
+/// Protocol misuse that a real device would reject or garble is noted in problems.
+/// Clones share the same state, so keep one to inspect after handing the other to the manager.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Rc<RefCell<SimState>>,
+}
+
+impl Simulator {
+    pub fn new(profile: &'static DeviceProfile) -> Self {
+        Simulator {
+            state: Rc::new(RefCell::new(SimState {
+                profile,
+                buckets: vec![None; profile.bucket_count as usize],
+                //the device starts on the liquid screen
+                mode: LcdMode::Liquid,
+                brightness: 100,
+                orientation: 0,
+                upload: None,
+                last_command: [0, 0],
+                problems: vec![],
+            })),
+        }
+    }
+
+    pub fn profile(&self) -> &'static DeviceProfile {
+        self.state.borrow().profile
+    }
+
+    pub fn mode(&self) -> LcdMode {
+        self.state.borrow().mode
+    }
+
+    pub fn bucket(&self, index: u8) -> Option<Bucket> {
+        self.state.borrow().buckets.get(index as usize).cloned()?
+    }
+
+    /// anything sent that the real device would not accept
+    pub fn problems(&self) -> Vec<String> {
+        self.state.borrow().problems.clone()
+    }
+
+    /// problems noted since the last call
+    pub fn take_problems(&self) -> Vec<String> {
+        std::mem::take(&mut self.state.borrow_mut().problems)
+    }
+
+    //base image of the current mode, before the lcd rotates and dims it
+    fn base_frames(&self) -> Result<Vec<Frame>, KrakenError> {
+        let state = self.state.borrow();
+        let size = state.profile.width;
+        let content = match state.mode {
+            LcdMode::Blank => None,
+            LcdMode::Liquid => {
+                let input = Input::new(LIQUID_TEMP, false);
+                let frame = imagetools::image_from_input(input, &Settings::default(), size, 0);
+                return Ok(vec![Frame::new(
+                    imagetools::frame_to_image(frame)
+                        .unwrap_or_else(|| DynamicImage::new_rgba8(size, size))
+                        .to_rgba8(),
+                )]);
+            }
+            LcdMode::Bucket(i) => state
+                .buckets
+                .get(i as usize)
+                .and_then(|b| b.as_ref())
+                .and_then(|b| b.content.clone()),
+        };
+        match content {
+            None => Ok(vec![Frame::new(
+                DynamicImage::new_rgba8(size, size).to_rgba8(),
+            )]),
+            Some((gif, true)) => {
+                let decode_error =
+                    |e: image::ImageError| KrakenError::ImageDecode(format!("gif: {e}"));
+                GifDecoder::new(Cursor::new(gif))
+                    .map_err(decode_error)?
+                    .into_frames()
+                    .collect_frames()
+                    .map_err(decode_error)
+            }
+            Some((frame, false)) => {
+                let len = frame.len();
+                let image = imagetools::frame_to_image(frame).ok_or_else(|| {
+                    KrakenError::ImageDecode(format!("{len} bytes is not a square rgba frame"))
+                })?;
+                Ok(vec![Frame::new(image.to_rgba8())])
+            }
+        }
+    }
+
+    //rotated by the lcd orientation and dimmed by its brightness
+    fn shown_frames(&self, mask: bool) -> Result<Vec<Frame>, KrakenError> {
+        let (orientation, brightness) = {
+            let state = self.state.borrow();
+            (state.orientation, state.brightness)
+        };
+        Ok(self
+            .base_frames()?
+            .into_iter()
+            .map(|frame| {
+                let delay = frame.delay();
+                let mut image = match orientation {
+                    90 => DynamicImage::ImageRgba8(frame.into_buffer()).rotate90(),
+                    180 => DynamicImage::ImageRgba8(frame.into_buffer()).rotate180(),
+                    270 => DynamicImage::ImageRgba8(frame.into_buffer()).rotate270(),
+                    _ => DynamicImage::ImageRgba8(frame.into_buffer()),
+                }
+                .to_rgba8();
+                for pixel in image.pixels_mut() {
+                    for c in pixel.0.iter_mut().take(3) {
+                        *c = (*c as u16 * brightness as u16 / 100) as u8;
+                    }
+                }
+                if mask {
+                    imagetools::circle_mask(&mut image);
+                }
+                Frame::from_parts(image, 0, 0, delay)
+            })
+            .collect())
+    }
+
+    /// What the lcd shows now, the first frame for gifs.
+    /// With mask, the corners the round lcd does not have are transparent
+    pub fn screen(&self, mask: bool) -> Result<DynamicImage, KrakenError> {
+        let frame = self
+            .shown_frames(mask)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| KrakenError::ImageDecode("gif has no frames".to_string()))?;
+        Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+    }
+
+    /// Save what the lcd shows now, animated if saved as .gif, otherwise the first frame
+    pub fn save(&self, path: &Path, mask: bool) -> Result<(), KrakenError> {
+        if !path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gif"))
+        {
+            return self
+                .screen(mask)?
+                .save(path)
+                .map_err(|e| KrakenError::file(path, e));
+        }
+        let file = std::fs::File::create(path).map_err(|e| KrakenError::file(path, e))?;
+        let mut encoder = GifEncoder::new(file);
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(|e| KrakenError::file(path, e))?;
+        encoder
+            .encode_frames(self.shown_frames(mask)?)
+            .map_err(|e| KrakenError::file(path, e))
+    }
+}
+
+impl SimState {
+    fn problem(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn bucket_mut(&mut self, index: u8) -> Option<&mut Bucket> {
+        self.buckets.get_mut(index as usize)?.as_mut()
+    }
+
+    fn interrupt(&mut self, p: &[u8]) {
+        match (p[0], p[1]) {
+            (0x30, 0x02) => {
+                self.brightness = p[3].min(100);
+                self.orientation = p[7] as u16 % 4 * 90;
+            }
+            (0x30, 0x04) => {
+                if let Some(bucket) = self.bucket_mut(p[2]) {
+                    bucket.content = None;
+                }
+            }
+            (0x32, 0x01) => self.setup_bucket(
+                p[2],
+                u16::from_le_bytes([p[4], p[5]]),
+                u16::from_le_bytes([p[6], p[7]]),
+            ),
+            (0x32, 0x02) => {
+                if let Some(bucket) = self.buckets.get_mut(p[2] as usize) {
+                    *bucket = None;
+                }
+            }
+            (0x36, 0x01) => {
+                if self.bucket_mut(p[2]).is_none() {
+                    self.problem(format!("bulk start for bucket {} that is not set up", p[2]));
+                }
+                self.upload = Some(Upload {
+                    bucket: p[2],
+                    header: None,
+                    data: vec![],
+                });
+            }
+            (0x36, 0x02) => self.finish_upload(),
+            (0x38, 0x01) => {
+                self.mode = match p[2] {
+                    2 => LcdMode::Liquid,
+                    4 => LcdMode::Bucket(p[3]),
+                    _ => LcdMode::Blank,
+                };
+                if let LcdMode::Bucket(i) = self.mode {
+                    if self.bucket_mut(i).is_none_or(|b| b.content.is_none()) {
+                        self.problem(format!("showing bucket {i} which has nothing uploaded"));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn setup_bucket(&mut self, index: u8, start: u16, blocks: u16) {
+        if index as usize >= self.buckets.len() {
+            self.problem(format!("bucket {index} does not exist"));
+            return;
+        }
+        let end = start as u32 + blocks as u32;
+        if end > self.profile.memory_blocks as u32 {
+            self.problem(format!(
+                "bucket {index} ends at block {end}, past the end of memory"
+            ));
+        }
+        let overlapping = self
+            .buckets
+            .iter()
+            .enumerate()
+            .filter_map(|(i, other)| Some((i, other.as_ref()?)))
+            .find(|(i, other)| {
+                *i != index as usize
+                    && (start as u32) < other.start as u32 + other.blocks as u32
+                    && end > other.start as u32
+            })
+            .map(|(i, _)| i);
+        if let Some(i) = overlapping {
+            self.problem(format!("bucket {index} overlaps bucket {i}"));
+        }
+        self.buckets[index as usize] = Some(Bucket {
+            start,
+            blocks,
+            content: None,
+        });
+    }
+
+    fn bulk(&mut self, data: &[u8]) {
+        let Some(upload) = self.upload.as_mut() else {
+            self.problem(format!(
+                "bulk write of {} bytes without a bulk start",
+                data.len()
+            ));
+            return;
+        };
+        match upload.header {
+            Some(_) => upload.data.extend_from_slice(data),
+            None if data.len() >= 20 && data[..12] == BULK_MAGIC => {
+                let size = u32::from_le_bytes([data[16], data[17], data[18], data[19]]);
+                upload.header = Some((data[12] == 1, size as usize));
+            }
+            None => self.problem("bulk data before the bulk header".to_string()),
+        }
+    }
+
+    fn finish_upload(&mut self) {
+        let Some(Upload {
+            bucket,
+            header,
+            mut data,
+        }) = self.upload.take()
+        else {
+            self.problem("bulk end without a bulk start".to_string());
+            return;
+        };
+        let Some((is_gif, size)) = header else {
+            self.problem(format!("upload to bucket {bucket} had no bulk header"));
+            return;
+        };
+        if data.len() < size {
+            self.problem(format!(
+                "upload to bucket {bucket} sent {} of {size} bytes",
+                data.len()
+            ));
+            return;
+        }
+        data.truncate(size);
+        let frame_size = self.profile.frame_size();
+        let Some(target) = self.bucket_mut(bucket) else {
+            return;
+        };
+        let capacity = target.blocks as usize * BLOCK_SIZE;
+        if size > capacity {
+            self.problem(format!(
+                "upload of {size} bytes overflows bucket {bucket} of {capacity} bytes"
+            ));
+            return;
+        }
+        target.content = Some((data, is_gif));
+        if !is_gif && size != frame_size {
+            self.problem(format!(
+                "rgba upload of {size} bytes is not a {frame_size} byte frame"
+            ));
+        }
+    }
+
+    fn reply(&self) -> [u8; 64] {
+        let mut reply = protocol::canned_reply(self.last_command);
+        if self.last_command == [0x30, 0x01] {
+            reply[24] = self.brightness;
+            reply[26] = (self.orientation / 90) as u8;
+        }
+        reply
+    }
+}
+
+impl Transport for Simulator {
+    fn write_interrupt(
+        &mut self,
+        _endpoint: u8,
+        bytes: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut packet = bytes.to_vec();
+        packet.resize(64, 0);
+        let mut state = self.state.borrow_mut();
+        state.last_command = [packet[0], packet[1]];
+        state.interrupt(&packet);
+        Ok(bytes.len())
+    }
+
+    fn read_interrupt(
+        &mut self,
+        _endpoint: u8,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let reply = self.state.borrow().reply();
+        let len = buf.len().min(reply.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        Ok(len)
+    }
+
+    fn write_bulk(
+        &mut self,
+        _endpoint: u8,
+        bytes: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.state.borrow_mut().bulk(bytes);
+        Ok(bytes.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        devices,
+        managerrusb::{DebugLevel, Manager},
+    };
+    use std::path::PathBuf;
+
+    fn manager() -> (Manager, Simulator) {
+        let simulator = Simulator::new(devices::default_profile());
+        (
+            Manager::simulated(DebugLevel::None, Settings::default(), simulator.clone()),
+            simulator,
+        )
+    }
+
+    fn send(simulator: &mut Simulator, packet: &[u8]) {
+        simulator
+            .write_interrupt(1, packet, Duration::ZERO)
+            .unwrap();
+    }
+
+    fn send_bulk(simulator: &mut Simulator, data: &[u8]) {
+        simulator.write_bulk(2, data, Duration::ZERO).unwrap();
+    }
+
+    fn header(is_gif: bool, size: usize) -> Vec<u8> {
+        let mut header = BULK_MAGIC.to_vec();
+        header.resize(512, 0);
+        header[12] = if is_gif { 1 } else { 2 };
+        header[16..20].copy_from_slice(&(size as u32).to_le_bytes());
+        header
+    }
+
+    //bucket 2 of 10 blocks from block 100, holding a gif of 3000 bytes
+    fn upload(simulator: &mut Simulator) {
+        send(simulator, &[0x32, 0x01, 2, 3, 100, 0, 10, 0, 1]);
+        send(simulator, &[0x36, 0x01, 2]);
+        send_bulk(simulator, &header(true, 3000));
+        send_bulk(simulator, &[7; 3000]);
+        send(simulator, &[0x36, 0x02]);
+    }
+
+    #[test]
+    fn upload_fills_bucket_and_shows_it() {
+        let mut simulator = Simulator::new(devices::default_profile());
+        upload(&mut simulator);
+        send(&mut simulator, &[0x38, 0x01, 4, 2]);
+        assert_eq!(simulator.problems(), Vec::<String>::new());
+        assert_eq!(simulator.mode(), LcdMode::Bucket(2));
+        let bucket = simulator.bucket(2).unwrap();
+        assert_eq!((bucket.start, bucket.blocks), (100, 10));
+        assert_eq!(bucket.content, Some((vec![7; 3000], true)));
+
+        send(&mut simulator, &[0x38, 0x01, 2]);
+        assert_eq!(simulator.mode(), LcdMode::Liquid);
+        send(&mut simulator, &[0x32, 0x02, 2]);
+        assert!(simulator.bucket(2).is_none());
+    }
+
+    #[test]
+    fn overlapping_buckets_are_a_problem() {
+        let mut simulator = Simulator::new(devices::default_profile());
+        upload(&mut simulator);
+        send(&mut simulator, &[0x32, 0x01, 3, 4, 105, 0, 10, 0, 1]);
+        assert_eq!(
+            simulator.take_problems(),
+            vec!["bucket 3 overlaps bucket 2"]
+        );
+        //right after the last is fine
+        send(&mut simulator, &[0x32, 0x01, 4, 5, 115, 0, 10, 0, 1]);
+        assert_eq!(simulator.problems(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn bulk_data_before_header_is_a_problem() {
+        let mut simulator = Simulator::new(devices::default_profile());
+        send(&mut simulator, &[0x32, 0x01, 2, 3, 0, 0, 10, 0, 1]);
+        send(&mut simulator, &[0x36, 0x01, 2]);
+        send_bulk(&mut simulator, &[7; 3000]);
+        send(&mut simulator, &[0x36, 0x02]);
+        assert_eq!(
+            simulator.problems(),
+            vec![
+                "bulk data before the bulk header",
+                "upload to bucket 2 had no bulk header"
+            ]
+        );
+        assert_eq!(simulator.bucket(2).unwrap().content, None);
+    }
+
+    #[test]
+    fn showing_empty_bucket_is_a_problem() {
+        let mut simulator = Simulator::new(devices::default_profile());
+        send(&mut simulator, &[0x38, 0x01, 4, 5]);
+        upload(&mut simulator);
+        //cleared buckets keep their place in memory, but not their content
+        send(&mut simulator, &[0x30, 0x04, 2]);
+        send(&mut simulator, &[0x38, 0x01, 4, 2]);
+        assert_eq!(
+            simulator.problems(),
+            vec![
+                "showing bucket 5 which has nothing uploaded",
+                "showing bucket 2 which has nothing uploaded"
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_upload_is_a_problem() {
+        let mut simulator = Simulator::new(devices::default_profile());
+        send(&mut simulator, &[0x32, 0x01, 2, 3, 0, 0, 1, 0, 1]);
+        send(&mut simulator, &[0x36, 0x01, 2]);
+        send_bulk(&mut simulator, &header(false, 3000));
+        send_bulk(&mut simulator, &[7; 1000]);
+        send(&mut simulator, &[0x36, 0x02]);
+        assert_eq!(
+            simulator.take_problems(),
+            vec!["upload to bucket 2 sent 1000 of 3000 bytes"]
+        );
+        send(&mut simulator, &[0x36, 0x01, 2]);
+        send_bulk(&mut simulator, &header(false, 3000));
+        send_bulk(&mut simulator, &[7; 3000]);
+        send(&mut simulator, &[0x36, 0x02]);
+        assert_eq!(
+            simulator.take_problems(),
+            vec!["upload of 3000 bytes overflows bucket 2 of 1024 bytes"]
+        );
+    }
+
+    //compare against testdata/golden, or write it when KRAKENCTL_UPDATE_GOLDEN is set
+    fn assert_golden(simulator: &Simulator, mask: bool, name: &str) {
+        assert_eq!(simulator.problems(), Vec::<String>::new());
+        let screen = simulator.screen(mask).unwrap().to_rgba8();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/golden")
+            .join(name);
+        if std::env::var_os("KRAKENCTL_UPDATE_GOLDEN").is_some() {
+            screen.save(&path).unwrap();
+        }
+        let golden = image::open(&path)
+            .unwrap_or_else(|e| panic!("{}: {e}", path.display()))
+            .to_rgba8();
+        assert!(
+            screen == golden,
+            "screen differs from {}, rerun with KRAKENCTL_UPDATE_GOLDEN=1 if the change is intended",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn values_match_golden() {
+        let (mut manager, simulator) = manager();
+        manager
+            .set_values_from_input("45°,34°;CPU,GPU", false)
+            .unwrap();
+        assert_golden(&simulator, false, "values.png");
+    }
+
+    #[test]
+    fn rotated_dimmed_masked_values_match_golden() {
+        let (mut manager, simulator) = manager();
+        manager.set_orientation(90).unwrap();
+        manager.set_brightness(50).unwrap();
+        manager.set_values_from_input("45°;CPU", false).unwrap();
+        assert_golden(&simulator, true, "values_rotated_masked.png");
+    }
+
+    #[test]
+    fn image_is_shown_upright_and_unmirrored() {
+        //red top left, green top right, blue bottom left, white bottom right
+        let source = image::RgbaImage::from_fn(320, 320, |x, y| match (x < 160, y < 160) {
+            (true, true) => image::Rgba([255, 0, 0, 255]),
+            (false, true) => image::Rgba([0, 255, 0, 255]),
+            (true, false) => image::Rgba([0, 0, 255, 255]),
+            (false, false) => image::Rgba([255, 255, 255, 255]),
+        });
+        let path =
+            std::env::temp_dir().join(format!("krakenctl-quadrants-{}.png", std::process::id()));
+        source.save(&path).unwrap();
+        let (mut manager, simulator) = manager();
+        manager.set_image(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let screen = simulator.screen(false).unwrap().to_rgba8();
+        assert_eq!(screen.get_pixel(40, 40), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(screen.get_pixel(280, 40), &image::Rgba([0, 255, 0, 255]));
+        assert_eq!(screen.get_pixel(40, 280), &image::Rgba([0, 0, 255, 255]));
+        assert_golden(&simulator, false, "image.png");
+    }
+
+    #[test]
+    fn liquid_matches_golden() {
+        let (mut manager, simulator) = manager();
+        manager.set_liquid().unwrap();
+        assert_golden(&simulator, false, "liquid.png");
+    }
+}
@@ -0,0 +1,444 @@
+use crate::{
+    devices::{self, DeviceProfile},
+    guard,
+    managerrusb::DebugLevel,
+};
+use rusb::{Context, Device, DeviceHandle, UsbContext};
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+pub const INTERRUPT_OUT: u8 = 1;
+pub const INTERRUPT_IN: u8 = 129;
+pub const BULK_OUT: u8 = 2;
+
+/// Type of usb transfer a packet was sent with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Interrupt,
+    Bulk,
+}
+
+/// Everything the manager needs from a connected device.
+/// Endpoints are passed through as-is so backends can tell interrupt and bulk traffic apart.
+pub trait Transport {
+    fn write_interrupt(
+        &mut self,
+        endpoint: u8,
+        bytes: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+    fn read_interrupt(
+        &mut self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+    fn write_bulk(&mut self, endpoint: u8, bytes: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    /// drop the session and open the device again, e.g. after the system resumes from suspend
+    fn reopen(&mut self) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> SessionStats {
+        SessionStats::default()
+    }
+
+    /// the usb device behind this transport, if there is one
+    fn info(&self) -> Option<&DeviceInfo> {
+        None
+    }
+
+    /// interfaces whose kernel driver was detached, to be reattached on close
+    fn detached_drivers(&self) -> Vec<u8> {
+        vec![]
+    }
+}
+
+/// How often the usb session was reused instead of reopening the device
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SessionStats {
+    pub opens: usize,
+    pub reuses: usize,
+    pub open_time: Duration,
+    /// times the device was opened again after the session was lost
+    pub reconnects: usize,
+}
+
+impl SessionStats {
+    /// time that reopening the device for every call would have cost
+    pub fn saved(&self) -> Duration {
+        if self.opens == 0 {
+            return Duration::ZERO;
+        }
+        self.open_time / self.opens as u32 * self.reuses as u32
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceSelector {
+    Serial(String),
+    BusAddress(u8, u8),
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    //bus:addr if both sides are numbers, otherwise a serial
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("device must be a serial or bus:addr".to_string());
+        }
+        if let Some((bus, addr)) = s.split_once(':') {
+            if let (Ok(bus), Ok(addr)) = (bus.parse(), addr.parse()) {
+                return Ok(DeviceSelector::BusAddress(bus, addr));
+            }
+        }
+        Ok(DeviceSelector::Serial(s.to_string()))
+    }
+}
+
+impl DeviceSelector {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Serial(serial) => info.serial.as_ref() == Some(serial),
+            DeviceSelector::BusAddress(bus, addr) => info.bus == *bus && info.address == *addr,
+        }
+    }
+}
+
+/// A connected device that has a profile in the registry
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub bus: u8,
+    pub address: u8,
+    pub port_path: Vec<u8>,
+    pub serial: Option<String>,
+    /// bcdDevice from the descriptor
+    pub hardware_revision: String,
+    pub profile: &'static DeviceProfile,
+}
+
+impl DeviceInfo {
+    /// port path in the same format as sysfs e.g. 1-4.2
+    pub fn port_string(&self) -> String {
+        let ports = self
+            .port_path
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join(".");
+        format!("{}-{}", self.bus, ports)
+    }
+}
+
+fn device_info(device: &Device<Context>) -> Option<DeviceInfo> {
+    let desc = device.device_descriptor().ok()?;
+    let profile = devices::find(desc.vendor_id(), desc.product_id())?;
+    //serial needs the device to be opened, which may not be permitted
+    let serial = device
+        .open()
+        .ok()
+        .and_then(|h| h.read_serial_number_string_ascii(&desc).ok());
+    let version = desc.device_version();
+    Some(DeviceInfo {
+        bus: device.bus_number(),
+        address: device.address(),
+        port_path: device.port_numbers().unwrap_or_default(),
+        serial,
+        hardware_revision: format!(
+            "{}.{}.{}",
+            version.major(),
+            version.minor(),
+            version.sub_minor()
+        ),
+        profile,
+    })
+}
+
+fn find_devices(context: &Context) -> rusb::Result<Vec<(Device<Context>, DeviceInfo)>> {
+    Ok(context
+        .devices()?
+        .iter()
+        .filter_map(|d| device_info(&d).map(|info| (d, info)))
+        .collect())
+}
+
+/// All connected devices that have a profile in the registry
+pub fn list_devices() -> rusb::Result<Vec<DeviceInfo>> {
+    let context = rusb::Context::new()?;
+    Ok(find_devices(&context)?
+        .into_iter()
+        .map(|(_, info)| info)
+        .collect())
+}
+
+/// Firmware of a listed device, read without resetting it.
+/// A kernel driver on the interrupt interface (usbhid on linux) is detached for the read
+/// and attached again after. None if the interface is in use by another program
+pub fn peek_firmware(info: &DeviceInfo, debug_level: DebugLevel) -> Option<(u8, u8, u8)> {
+    let handle = open_at(info.bus, &info.port_path, debug_level).ok()?;
+    //platforms without kernel driver support report NotSupported, and have nothing to detach
+    let detached =
+        handle.kernel_driver_active(1).unwrap_or(false) && handle.detach_kernel_driver(1).is_ok();
+    let firmware = read_firmware(&handle);
+    if detached && handle.attach_kernel_driver(1).is_err() && debug_level >= DebugLevel::Warning {
+        println!("Could not reattach the kernel driver of interface 1");
+    }
+    firmware
+}
+
+fn read_firmware(handle: &DeviceHandle<Context>) -> Option<(u8, u8, u8)> {
+    handle.claim_interface(1).ok()?;
+    let timeout = Duration::from_millis(200);
+    let mut request = [0u8; 64];
+    request[..2].copy_from_slice(&[0x10, 0x01]);
+    let mut reply = [0u8; 64];
+    let firmware = handle
+        .write_interrupt(INTERRUPT_OUT, &request, timeout)
+        .ok()
+        .and_then(|_| {
+            //skip stale reports, as the manager does
+            (0..8).find_map(|_| {
+                let read = handle
+                    .read_interrupt(INTERRUPT_IN, &mut reply, timeout)
+                    .ok()?;
+                (read == reply.len() && reply[..2] == [0x11, 0x01])
+                    .then(|| (reply[17], reply[18], reply[19]))
+            })
+        });
+    let _ = handle.release_interface(1);
+    firmware
+}
+
+/// Open the device plugged into a port.
+/// The port path stays the same if the device re-enumerates, unlike the address
+pub(crate) fn open_at(
+    bus: u8,
+    port_path: &[u8],
+    debug_level: DebugLevel,
+) -> rusb::Result<DeviceHandle<Context>> {
+    let mut context = rusb::Context::new()?;
+    context.set_log_level(debug_level.into());
+    let device = context
+        .devices()?
+        .iter()
+        .find(|d| d.bus_number() == bus && d.port_numbers().unwrap_or_default() == port_path)
+        .ok_or(rusb::Error::NoDevice)?;
+    device.open()
+}
+
+/// Talks to a real device through libusb
+pub struct RusbTransport {
+    info: DeviceInfo,
+    debug_level: DebugLevel,
+    kernel_drivers: Vec<u8>,
+    handle: Option<DeviceHandle<Context>>,
+    stats: SessionStats,
+}
+
+impl RusbTransport {
+    /// Open the device matching the selector, or the first supported device if none given
+    pub fn open(
+        selector: Option<&DeviceSelector>,
+        debug_level: DebugLevel,
+    ) -> rusb::Result<(Self, &'static DeviceProfile)> {
+        let context = rusb::Context::new()?;
+        let found = find_devices(&context)?;
+        if selector.is_none() && found.len() > 1 && debug_level >= DebugLevel::Warning {
+            println!(
+                "{} devices found, using the first. Use --device to choose",
+                found.len()
+            );
+        }
+        let (device, info) = found
+            .into_iter()
+            .find(|(_, info)| selector.is_none_or(|s| s.matches(info)))
+            .ok_or(rusb::Error::NoDevice)?;
+        if debug_level >= DebugLevel::Info {
+            println!(
+                "found {} at {}:{} port {}",
+                info.profile.name,
+                info.bus,
+                info.address,
+                info.port_string()
+            );
+        }
+
+        let device_handle = device.open()?;
+        device_handle.reset()?;
+
+        if debug_level >= DebugLevel::Info {
+            println!("config: {:?}", device_handle.active_configuration());
+        }
+
+        let profile = info.profile;
+        let mut transport = RusbTransport {
+            info,
+            debug_level,
+            kernel_drivers: vec![],
+            handle: None,
+            stats: SessionStats::default(),
+        };
+        let start = Instant::now();
+        transport.attach(device_handle)?;
+        transport.stats.opens += 1;
+        transport.stats.open_time += start.elapsed();
+        Ok((transport, profile))
+    }
+
+    fn debug(&self, string: impl ToString, level: DebugLevel) {
+        if self.debug_level >= level {
+            println!("{}", string.to_string());
+        }
+        if level == DebugLevel::Error {
+            eprintln!("{}", string.to_string());
+        }
+    }
+
+    fn get_handle(&self) -> rusb::Result<DeviceHandle<Context>> {
+        open_at(self.info.bus, &self.info.port_path, self.debug_level)
+    }
+
+    //detach kernel drivers and claim both interfaces, keeping the handle for the session
+    //interface 0 holds the bulk endpoint, interface 1 the interrupt endpoints
+    fn attach(&mut self, device_handle: DeviceHandle<Context>) -> rusb::Result<()> {
+        if cfg!(target_os = "linux") {
+            for i in [0, 1] {
+                let has_k = device_handle.kernel_driver_active(i).unwrap_or_default();
+                if has_k {
+                    device_handle.detach_kernel_driver(i)?;
+                    if !self.kernel_drivers.contains(&i) {
+                        self.kernel_drivers.push(i);
+                    }
+                    guard::register(&self.info, &self.kernel_drivers, self.debug_level);
+                    self.debug(format!("kernel detach:{i:?}"), DebugLevel::Debug);
+                } else {
+                    self.debug(format!("kernel driver not active:{i:?}"), DebugLevel::Debug);
+                }
+            }
+        }
+        device_handle.set_active_configuration(1)?;
+        for i in [0, 1] {
+            device_handle.claim_interface(i)?;
+        }
+        self.handle = Some(device_handle);
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> rusb::Result<()> {
+        self.handle = None;
+        let start = Instant::now();
+        let device_handle = self.get_handle()?;
+        self.attach(device_handle)?;
+        self.stats.opens += 1;
+        self.stats.reconnects += 1;
+        self.stats.open_time += start.elapsed();
+        Ok(())
+    }
+
+    //run on the session handle, reconnecting once if the device went away
+    fn with_handle<T>(
+        &mut self,
+        mut func: impl FnMut(&DeviceHandle<Context>) -> rusb::Result<T>,
+    ) -> rusb::Result<T> {
+        match &self.handle {
+            Some(handle) => {
+                self.stats.reuses += 1;
+                match func(handle) {
+                    Err(rusb::Error::NoDevice) | Err(rusb::Error::Io) => {
+                        self.debug("Lost device, reconnecting", DebugLevel::Warning);
+                    }
+                    result => return result,
+                }
+            }
+            None => self.debug("No device session, reconnecting", DebugLevel::Info),
+        }
+        self.reconnect()?;
+        func(self.handle.as_ref().ok_or(rusb::Error::NoDevice)?)
+    }
+}
+
+impl Transport for RusbTransport {
+    fn write_interrupt(
+        &mut self,
+        endpoint: u8,
+        bytes: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.with_handle(|dev| dev.write_interrupt(endpoint, bytes, timeout))
+    }
+
+    fn read_interrupt(
+        &mut self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.with_handle(|dev| dev.read_interrupt(endpoint, buf, timeout))
+    }
+
+    fn write_bulk(&mut self, endpoint: u8, bytes: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        self.with_handle(|dev| dev.write_bulk(endpoint, bytes, timeout))
+    }
+
+    fn reopen(&mut self) -> rusb::Result<()> {
+        self.debug("Reopening device", DebugLevel::Info);
+        self.reconnect()
+    }
+
+    fn stats(&self) -> SessionStats {
+        self.stats
+    }
+
+    fn info(&self) -> Option<&DeviceInfo> {
+        Some(&self.info)
+    }
+
+    fn detached_drivers(&self) -> Vec<u8> {
+        self.kernel_drivers.clone()
+    }
+}
+
+impl Drop for RusbTransport {
+    fn drop(&mut self) {
+        let handle = match self.handle.take() {
+            Some(handle) => Ok(handle),
+            None => self.get_handle(),
+        };
+        if let Ok(device_handle) = handle {
+            for i in [0, 1] {
+                if device_handle.release_interface(i).is_err() {
+                    self.debug("Could not release interface", DebugLevel::Debug);
+                }
+            }
+            if let Err(_e) = device_handle.reset() {
+                self.debug("Could not reset device", DebugLevel::Error);
+            }
+
+            if cfg!(target_os = "linux") {
+                if self.kernel_drivers.is_empty() {
+                    self.debug("No kernel drivers to detach", DebugLevel::Info);
+                }
+                for &i in self.kernel_drivers.iter() {
+                    if device_handle.attach_kernel_driver(i).is_ok() {
+                        self.debug(format!("kernel attach:{i:?}"), DebugLevel::Debug);
+                    } else {
+                        self.debug("could not attach kernel driver", DebugLevel::Debug);
+                    }
+                }
+                if device_handle.reset().is_err() {
+                    self.debug("Could not reset device handle", DebugLevel::Error);
+                }
+            }
+        } else {
+            self.debug(
+                "Could not open device handle when closing manager",
+                DebugLevel::Error,
+            );
+        }
+        guard::clear();
+    }
+}